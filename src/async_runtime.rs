@@ -0,0 +1,164 @@
+use std::{io, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    runtime,
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    connection::{handle_connection_async, reject_connection_async, AsyncHandler},
+    listener::Listener,
+    reload::Reloader,
+    request::Request,
+    route::RouteFuture,
+    shutdown::Shutdown,
+    tls::{self, Tls},
+};
+
+/// How often the drain checks whether every connection has finished
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+/// Serves every listener on an async runtime until shutdown, then waits for connections to finish
+///
+/// `threads` sets the number of runtime workers. Sync handlers and static files are run with
+/// `block_in_place`, so a slow one only holds up the worker it's running on
+pub fn run(
+    listeners: Vec<(Listener, Option<Arc<Tls>>)>,
+    redirect: Option<(Listener, u16)>,
+    threads: Option<usize>,
+    reloader: &Arc<Reloader>,
+    shutdown: &Arc<Shutdown>,
+    deadline: Duration,
+) -> Result<()> {
+    let mut builder = runtime::Builder::new_multi_thread();
+    if let Some(threads) = threads.filter(|threads| *threads > 0) {
+        builder.worker_threads(threads);
+    }
+    let runtime = builder
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime")?;
+
+    runtime.block_on(async {
+        let mut tasks = JoinSet::new();
+        for (listener, tls) in listeners {
+            let handler: Arc<dyn AsyncHandler> = reloader.clone();
+            tasks.spawn(serve(
+                listener,
+                tls,
+                handler,
+                shutdown.clone(),
+                reloader.clone(),
+            ));
+        }
+        if let Some((listener, https_port)) = redirect {
+            let handler: Arc<dyn AsyncHandler> = Arc::new(move |request: Request| -> RouteFuture {
+                Box::pin(async move { Ok(tls::redirect_to_https(&request, https_port)) })
+            });
+            tasks.spawn(serve(
+                listener,
+                None,
+                handler,
+                shutdown.clone(),
+                reloader.clone(),
+            ));
+        }
+        while let Some(result) = tasks.join_next().await {
+            if result.is_err() {
+                error!("Listener task panicked");
+            }
+        }
+        drain(shutdown, deadline).await;
+    });
+    // Anything still running after the deadline is dropped rather than waited on
+    runtime.shutdown_background();
+    Ok(())
+}
+
+/// Accepts connections until shutdown, spawning a task for each one
+async fn serve(
+    mut listener: Listener,
+    tls: Option<Arc<Tls>>,
+    handler: Arc<dyn AsyncHandler>,
+    shutdown: Arc<Shutdown>,
+    reloader: Arc<Reloader>,
+) {
+    let _guard = shutdown.track_listener();
+    let accepting = match listener.to_async() {
+        Ok(accepting) => accepting,
+        Err(err) => {
+            error!("Failed to register listener with the async runtime with error: {err}");
+            return;
+        }
+    };
+    loop {
+        let stream = match accepting.accept().await {
+            Ok(stream) => stream,
+            Err(err) if shutdown.is_triggered() => {
+                error!("Failed to accept connection with error: {err}");
+                break;
+            }
+            Err(err) => {
+                error!("Failed to accept connection with error: {err}");
+                continue;
+            }
+        };
+        let limits = reloader.live().limits;
+        let (tls, handler, connection_shutdown) = (tls.clone(), handler.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            // The handshake happens here rather than in the accept loop, so a slow client can't
+            // hold up everyone else
+            let stream = match tls {
+                Some(tls) => match tls.accept_async(stream).await {
+                    Ok(stream) => stream,
+                    // Closed without starting a handshake, ie: the shutdown waking us up
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return,
+                    Err(err) => {
+                        error!("TLS handshake failed with error: {err}");
+                        return;
+                    }
+                },
+                None => stream,
+            };
+            let open = connection_shutdown.active_connections();
+            if limits.max_pending_connections > 0 && open >= limits.max_pending_connections {
+                warn!("{open} connections open, rejecting new connection");
+                reject_connection_async(stream, &connection_shutdown).await;
+                return;
+            }
+            handle_connection_async(stream, &connection_shutdown, limits, &handler).await;
+        });
+        if shutdown.is_triggered() {
+            break;
+        }
+    }
+    drop(accepting);
+    if shutdown.is_handed_off() {
+        // The new process is still listening on it
+        listener.keep_socket_file();
+    }
+}
+
+/// Waits for every in-flight connection to finish, up to the deadline
+async fn drain(shutdown: &Shutdown, deadline: Duration) {
+    info!(
+        "Waiting up to {deadline:?} for {} connections to finish",
+        shutdown.active_connections()
+    );
+    let finished = timeout(deadline, async {
+        // Sleeping first gives connections that were only just spawned a chance to be counted
+        loop {
+            sleep(DRAIN_POLL).await;
+            if shutdown.active_connections() == 0 {
+                break;
+            }
+        }
+    })
+    .await;
+    if finished.is_err() {
+        warn!("Shutdown deadline exceeded, dropping remaining connections");
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// How the upstream for each request is picked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight, ties are broken round-robin
+    LeastConnections,
+    /// Clients keep going to the same upstream for as long as it's available, clients without an
+    /// IP address, ie: over a Unix socket, fall back to round-robin
+    IpHash,
+}
+
+/// Sends a GET for `path` to every upstream each `interval`, upstreams that don't answer with a 2xx
+/// or 3xx are taken out of rotation until they do
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct BalanceOptions {
+    pub balance: Balance,
+    /// Consecutive failed requests before an upstream is ejected, 0 disables ejection
+    pub max_fails: u32,
+    /// How long an ejected upstream is left out of rotation
+    pub fail_timeout: Duration,
+    pub health_check: Option<HealthCheck>,
+}
+
+impl Default for BalanceOptions {
+    fn default() -> Self {
+        Self {
+            balance: Balance::RoundRobin,
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+            health_check: None,
+        }
+    }
+}
+
+/// A single upstream HTTP/1.1 server
+#[derive(Debug)]
+pub struct Upstream {
+    /// The upstream's `host:port`, as it's written in the URL
+    authority: String,
+    base_path: Option<String>,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    /// Only ever cleared by the active health check
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    /// `url` must be an `http://` URL, TLS upstreams aren't supported
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("Upstream must be an http:// URL: {url}"))?;
+        let (authority, path) = rest
+            .find('/')
+            .map_or((rest, ""), |index| rest.split_at(index));
+        if authority.is_empty() {
+            return Err(anyhow!("Upstream is missing a host: {url}"));
+        }
+        let path = path.trim_end_matches('/');
+        Ok(Self {
+            authority: authority.to_string(),
+            base_path: (!path.is_empty()).then(|| path.to_string()),
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    /// The path from the upstream's URL, which replaces the proxy's prefix
+    pub fn base_path(&self) -> Option<&str> {
+        self.base_path.as_deref()
+    }
+
+    pub fn connect(&self, timeout: Duration) -> Result<TcpStream> {
+        let address = if self.authority.contains(':') {
+            self.authority.clone()
+        } else {
+            format!("{}:80", self.authority)
+        };
+        let mut last_err = None;
+        for address in address
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve upstream {}", self.authority))?
+        {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.map_or_else(
+            || anyhow!("Upstream {} resolved to no addresses", self.authority),
+            |err| {
+                anyhow::Error::new(err)
+                    .context(format!("Failed to connect to upstream {}", self.authority))
+            },
+        ))
+    }
+
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self
+                .ejected_until
+                .lock()
+                .unwrap()
+                .is_none_or(|until| Instant::now() >= until)
+    }
+
+    /// Whether the upstream answers a GET for `path` with a 2xx or 3xx
+    fn probe(&self, path: &str, timeout: Duration) -> Result<bool> {
+        let mut stream = self.connect(timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            self.authority
+        )?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        Ok(line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .is_some_and(|code| (200..400).contains(&code)))
+    }
+}
+
+/// The upstreams for a proxy route, along with what's needed to pick between them
+///
+/// This is shared between every copy of the route, so the balancing and health state survives the
+/// routes being cloned for each host
+#[derive(Debug)]
+pub struct Pool {
+    upstreams: Vec<Upstream>,
+    options: BalanceOptions,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    /// Starts the active health check, if there is one, which stops once the pool is dropped
+    pub fn new(urls: &[String], options: BalanceOptions) -> Result<Arc<Self>> {
+        if urls.is_empty() {
+            return Err(anyhow!("At least one upstream is required"));
+        }
+        let upstreams = urls
+            .iter()
+            .map(|url| Upstream::parse(url))
+            .collect::<Result<Vec<_>>>()?;
+        let pool = Arc::new(Self {
+            upstreams,
+            options,
+            next: AtomicUsize::new(0),
+        });
+        if let Some(check) = pool.options.health_check.clone() {
+            spawn_health_check(Arc::downgrade(&pool), check);
+        }
+        Ok(pool)
+    }
+
+    /// The upstreams in the order they should be tried for a request from `client`
+    ///
+    /// Upstreams that are ejected or failing their health check are skipped, unless every one of
+    /// them is, as trying them is still better than failing outright
+    pub fn candidates(&self, client: Option<IpAddr>) -> Vec<usize> {
+        let count = self.upstreams.len();
+        let start = match (self.options.balance, client) {
+            (Balance::IpHash, Some(ip)) => {
+                let mut hasher = DefaultHasher::new();
+                ip.hash(&mut hasher);
+                usize::try_from(hasher.finish() % count as u64).unwrap_or_default()
+            }
+            _ => self.next.fetch_add(1, Ordering::Relaxed) % count,
+        };
+        let mut order: Vec<usize> = (0..count).map(|offset| (start + offset) % count).collect();
+        if self.options.balance == Balance::LeastConnections {
+            // The sort is stable, so the rotation above breaks ties
+            order.sort_by_key(|&index| self.upstreams[index].active.load(Ordering::Relaxed));
+        }
+        let available: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&index| self.upstreams[index].is_available())
+            .collect();
+        if available.is_empty() {
+            order
+        } else {
+            available
+        }
+    }
+
+    /// Counts a request as in flight on the upstream until the lease is dropped
+    pub fn lease(self: &Arc<Self>, index: usize) -> Lease {
+        self.upstreams[index].active.fetch_add(1, Ordering::Relaxed);
+        Lease {
+            pool: self.clone(),
+            index,
+        }
+    }
+}
+
+/// A request in flight on an upstream, which reports back how it went for the passive health
+/// check
+#[derive(Debug)]
+pub struct Lease {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Lease {
+    pub fn upstream(&self) -> &Upstream {
+        &self.pool.upstreams[self.index]
+    }
+
+    pub fn succeeded(&self) {
+        self.upstream().failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Ejects the upstream once it has failed `max_fails` times in a row
+    pub fn failed(&self) {
+        let options = &self.pool.options;
+        if options.max_fails == 0 {
+            return;
+        }
+        let upstream = self.upstream();
+        if upstream.failures.fetch_add(1, Ordering::Relaxed) + 1 < options.max_fails {
+            return;
+        }
+        upstream.failures.store(0, Ordering::Relaxed);
+        *upstream.ejected_until.lock().unwrap() = Some(Instant::now() + options.fail_timeout);
+        warn!(
+            "Upstream {} failed {} times in a row, ejecting it for {:?}",
+            upstream.authority, options.max_fails, options.fail_timeout
+        );
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.upstream().active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs until the routes are replaced or dropped, as there's nothing left to check after that
+fn spawn_health_check(pool: Weak<Pool>, check: HealthCheck) {
+    thread::spawn(move || {
+        while let Some(pool) = pool.upgrade() {
+            for upstream in &pool.upstreams {
+                // A probe that takes longer than the interval counts as a failure
+                let healthy = upstream.probe(&check.path, check.interval).unwrap_or(false);
+                let was_healthy = upstream.healthy.swap(healthy, Ordering::Relaxed);
+                if was_healthy && !healthy {
+                    warn!(
+                        "Upstream {} failed its health check, taking it out of rotation",
+                        upstream.authority
+                    );
+                } else if !was_healthy && healthy {
+                    info!(
+                        "Upstream {} passed its health check, returning it to rotation",
+                        upstream.authority
+                    );
+                }
+            }
+            drop(pool);
+            thread::sleep(check.interval);
+        }
+    });
+}
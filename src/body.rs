@@ -0,0 +1,294 @@
+use std::{
+    fmt::{self, Debug},
+    io::{self, BufRead, Read},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// How much of a streamed body is read before it's written out as a chunk
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A response body that's sent to the client as it's read, rather than held in memory
+///
+/// Bodies of unknown length are sent with chunked transfer coding
+pub struct BodyStream {
+    reader: Box<dyn Read + Send>,
+    length: Option<u64>,
+}
+
+impl BodyStream {
+    pub fn new<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> Self {
+        Self {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    pub const fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// The body without any framing, for protocols that frame it themselves, ie: HTTP/2
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        match self.length {
+            Some(length) => Box::new(self.reader.take(length)),
+            None => self.reader,
+        }
+    }
+
+    /// The body sent as it is, ending when the connection closes, for clients that can't read
+    /// chunked bodies
+    pub fn unframed(self) -> FramedBody {
+        FramedBody::Unframed(self.into_reader())
+    }
+
+    /// The body as it should be written to the connection, framing included
+    pub fn framed(self) -> FramedBody {
+        match self.length {
+            Some(remaining) => FramedBody::Sized {
+                reader: self.reader,
+                remaining,
+            },
+            None => FramedBody::Chunked {
+                reader: self.reader,
+                pending: Vec::new(),
+                position: 0,
+                done: false,
+            },
+        }
+    }
+}
+
+pub enum FramedBody {
+    Unframed(Box<dyn Read + Send>),
+    Sized {
+        reader: Box<dyn Read + Send>,
+        remaining: u64,
+    },
+    Chunked {
+        reader: Box<dyn Read + Send>,
+        pending: Vec<u8>,
+        position: usize,
+        done: bool,
+    },
+}
+
+impl Read for FramedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unframed(reader) => reader.read(buf),
+            Self::Sized { reader, remaining } => {
+                if *remaining == 0 || buf.is_empty() {
+                    return Ok(0);
+                }
+                let limit = usize::try_from(*remaining).map_or(buf.len(), |r| r.min(buf.len()));
+                let read = reader.read(&mut buf[..limit])?;
+                if read == 0 {
+                    // The client is expecting more, so the connection can't be used after this
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Body ended before its Content-Length",
+                    ));
+                }
+                *remaining -= read as u64;
+                Ok(read)
+            }
+            Self::Chunked {
+                reader,
+                pending,
+                position,
+                done,
+            } => {
+                if *position == pending.len() {
+                    if *done {
+                        return Ok(0);
+                    }
+                    let mut chunk = vec![0; CHUNK_SIZE];
+                    let read = reader.read(&mut chunk)?;
+                    pending.clear();
+                    *position = 0;
+                    if read == 0 {
+                        pending.extend_from_slice(b"0\r\n\r\n");
+                        *done = true;
+                    } else {
+                        pending.extend_from_slice(format!("{read:x}\r\n").as_bytes());
+                        pending.extend_from_slice(&chunk[..read]);
+                        pending.extend_from_slice(b"\r\n");
+                    }
+                }
+                let read = (&pending[*position..]).read(buf)?;
+                *position += read;
+                Ok(read)
+            }
+        }
+    }
+}
+
+/// Decodes a body sent with chunked transfer coding, discarding any trailers
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub const fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            // Chunk extensions aren't used for anything, so they're ignored
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid chunk size: {size}"),
+                )
+            })?;
+            if self.remaining == 0 {
+                while !self.read_line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let limit = usize::try_from(self.remaining).map_or(buf.len(), |r| r.min(buf.len()));
+        let read = self.inner.read(&mut buf[..limit])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Missing CRLF after chunk",
+            ));
+        }
+        Ok(read)
+    }
+}
+
+/// A connection lent to a request for as long as its handler runs, so the handler can read the body
+/// as it arrives rather than it being read up front
+pub struct LentBody<T> {
+    lent: Arc<Mutex<Lent<T>>>,
+}
+
+struct Lent<T> {
+    /// Taken back by the connection once the handler returns
+    source: Option<T>,
+    remaining: u64,
+}
+
+impl<T: Read + Send + 'static> LentBody<T> {
+    pub fn new(source: T, length: u64) -> Self {
+        Self {
+            lent: Arc::new(Mutex::new(Lent {
+                source: Some(source),
+                remaining: length,
+            })),
+        }
+    }
+
+    /// What the request reads the body through
+    pub fn reader(&self) -> BodyReader {
+        BodyReader {
+            lent: self.lent.clone(),
+        }
+    }
+
+    /// Takes the connection back once the handler is done with it
+    ///
+    /// A handler that's still running after a timeout may be in the middle of a read, which is
+    /// waited for, after that its reads fail
+    pub fn take_back(self) -> T {
+        self.lent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .source
+            .take()
+            .expect("Only the connection takes the source back")
+    }
+}
+
+trait BodySource: Send {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn remaining(&self) -> u64;
+}
+
+impl<T: Read + Send> BodySource for Lent<T> {
+    fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(source) = self.source.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Request body read after the response was sent",
+            ));
+        };
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let limit = usize::try_from(self.remaining).map_or(buf.len(), |r| r.min(buf.len()));
+        let read = source.read(&mut buf[..limit])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Body ended before its Content-Length",
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// A request body that's read from the connection as the handler asks for it, which every clone
+/// of the request shares
+#[derive(Clone)]
+pub struct BodyReader {
+    lent: Arc<Mutex<dyn BodySource>>,
+}
+
+impl BodyReader {
+    /// Whether the whole body has been read, the connection can't be used again otherwise
+    pub fn is_done(&self) -> bool {
+        // A handler that panicked mid read leaves the count as it was
+        self.lent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remaining()
+            == 0
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lent.lock().unwrap().read(buf)
+    }
+}
+
+impl Debug for BodyReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyReader").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::warn;
+
+use crate::{
+    body::BodyStream,
+    codes::ResponseCode,
+    fastcgi,
+    headers::Headers,
+    listener::Address,
+    request::{encode_path, Request},
+    route::{gateway_failed, normalize_prefix, strip_prefix, RouteResponse},
+};
+
+/// How long a program or FastCGI server has to produce its response
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most a program or FastCGI server may write, as responses are read in full before they're
+/// sent. Anything larger gets a 502
+const MAX_OUTPUT: usize = 16 * 1024 * 1024;
+/// How often a program that has closed stdout is checked for having exited
+const EXIT_POLL: Duration = Duration::from_millis(10);
+
+/// Where the response for a CGI route comes from
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// A program that's run once per request
+    Program(PathBuf),
+    /// A FastCGI server, ie: php-fpm, which is told to run `script` if one is provided
+    FastCgi {
+        address: Address,
+        script: Option<PathBuf>,
+    },
+}
+
+/// Serves a prefix, and everything below it, with a CGI program or a FastCGI server
+///
+/// The part of the target below the prefix is passed on as `PATH_INFO`. Responses are read in full
+/// before they're sent, and a `Location` header without a `Status` is sent as a 302 rather than
+/// handled as a local redirect.
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    prefix: String,
+    backend: Backend,
+    timeout: Duration,
+}
+
+impl Cgi {
+    pub fn new(prefix: &str, backend: Backend, timeout: Option<Duration>) -> Self {
+        Self {
+            prefix: normalize_prefix(prefix),
+            backend,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Programs that can't be run, or produce invalid or too much output get a 502, ones that
+    /// don't finish within the timeout get a 504
+    pub fn run(&self, request: &Request) -> RouteResponse {
+        let body = request.body_bytes().unwrap_or_default();
+        let variables = self.variables(request);
+        let deadline = Instant::now() + self.timeout;
+        let output = match &self.backend {
+            Backend::Program(program) => {
+                run_program(program, &variables, body, deadline, MAX_OUTPUT)
+            }
+            Backend::FastCgi { address, .. } => {
+                fastcgi::run(address, &variables, body, deadline, MAX_OUTPUT)
+            }
+        };
+        output
+            .and_then(parse_output)
+            .unwrap_or_else(|err| gateway_failed(request, &err.context("CGI request failed")))
+    }
+
+    /// The meta-variables from RFC 3875, along with a few that are commonly expected
+    fn variables(&self, request: &Request) -> Vec<(String, String)> {
+        let target = request.target();
+        let path_info = strip_prefix(&self.prefix, target).unwrap_or(target);
+        let script_name = if self.prefix == "/" { "" } else { &self.prefix };
+        let (server_name, server_port) = split_host(request.host().unwrap_or_default());
+        let default_port = if request.is_secure() { "443" } else { "80" };
+        let server_port = server_port.unwrap_or(default_port);
+        let query = request.query().map_or("", String::as_str);
+        let mut request_uri = encode_path(target);
+        if !query.is_empty() {
+            request_uri.push('?');
+            request_uri.push_str(query);
+        }
+
+        let mut variables = vec![
+            ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+            (
+                "SERVER_SOFTWARE",
+                format!("webserver/{}", env!("CARGO_PKG_VERSION")),
+            ),
+            ("SERVER_PROTOCOL", request.version().clone()),
+            ("SERVER_NAME", server_name.to_string()),
+            ("SERVER_PORT", server_port.to_string()),
+            ("REQUEST_METHOD", request.method().to_string()),
+            (
+                "REQUEST_SCHEME",
+                String::from(if request.is_secure() { "https" } else { "http" }),
+            ),
+            ("REQUEST_URI", request_uri),
+            ("SCRIPT_NAME", script_name.to_string()),
+            ("PATH_INFO", path_info.to_string()),
+            ("QUERY_STRING", query.to_string()),
+        ];
+        if let Some(addr) = request.peer_addr() {
+            variables.push(("REMOTE_ADDR", addr.ip().to_string()));
+            variables.push(("REMOTE_PORT", addr.port().to_string()));
+        }
+        if request.is_secure() {
+            variables.push(("HTTPS", String::from("on")));
+        }
+        if let Some(body) = request.body_bytes() {
+            variables.push(("CONTENT_LENGTH", body.len().to_string()));
+        }
+        if let Some(content_type) = request.headers().get("Content-Type") {
+            variables.push(("CONTENT_TYPE", content_type.to_string()));
+        }
+        let script = match &self.backend {
+            Backend::Program(program) => Some(program),
+            Backend::FastCgi { script, .. } => script.as_ref(),
+        };
+        if let Some(script) = script {
+            variables.push(("SCRIPT_FILENAME", script.display().to_string()));
+        }
+
+        let mut variables: Vec<(String, String)> = variables
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        for (name, value) in request.headers().iter() {
+            // Proxy is skipped as programs would mistake it for HTTP_PROXY, see httpoxy
+            if ["Content-Length", "Content-Type", "Proxy"]
+                .iter()
+                .any(|skipped| skipped.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+            if let Some((_, existing)) = variables.iter_mut().find(|(key, _)| *key == name) {
+                existing.push_str(", ");
+                existing.push_str(value);
+            } else {
+                variables.push((name, value.to_string()));
+            }
+        }
+        variables
+    }
+}
+
+/// Splits a `Host` header into the name and port, leaving IPv6 addresses in their brackets
+fn split_host(host: &str) -> (&str, Option<&str>) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, Some(port)),
+        _ => (host, None),
+    }
+}
+
+/// Runs the program with the request body on stdin, returning everything it wrote to stdout
+///
+/// The program is killed if it doesn't finish before the deadline, or writes more than
+/// `max_output` bytes. Anything written to stderr is logged.
+fn run_program(
+    program: &Path,
+    variables: &[(String, String)],
+    body: &[u8],
+    deadline: Instant,
+    max_output: usize,
+) -> Result<Vec<u8>> {
+    let mut command = Command::new(program);
+    command
+        .env_clear()
+        .envs(variables.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Without a PATH even scripts using `#!/usr/bin/env` can't start
+    if let Some(path) = env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    if let Some(dir) = program.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        command.current_dir(dir);
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to run CGI program {}", program.display()))?;
+
+    let (mut stdin, stdout, stderr) = (
+        child.stdin.take().context("CGI program has no stdin")?,
+        child.stdout.take().context("CGI program has no stdout")?,
+        child.stderr.take().context("CGI program has no stderr")?,
+    );
+    let body = body.to_vec();
+    // Programs are free to ignore their input, so failing to write it isn't an error
+    thread::spawn(move || {
+        let _ = stdin.write_all(&body);
+    });
+    let name = program.display().to_string();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            warn!("CGI program {name} wrote to stderr: {line}");
+        }
+    });
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // A byte past the limit is enough to tell the output is too large
+        let mut output = Vec::new();
+        let read = stdout.take(max_output as u64 + 1).read_to_end(&mut output);
+        let _ = sender.send(read.map(|_| output));
+    });
+
+    let output = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(Ok(output)) if output.len() <= max_output => output,
+        Ok(Ok(_)) => {
+            stop(&mut child);
+            return Err(anyhow!(
+                "CGI program {} wrote more than {max_output} bytes",
+                program.display()
+            ));
+        }
+        Ok(Err(err)) => {
+            stop(&mut child);
+            return Err(anyhow::Error::new(err).context("Failed to read CGI output"));
+        }
+        Err(_) => {
+            stop(&mut child);
+            return Err(
+                anyhow::Error::new(io::Error::from(io::ErrorKind::TimedOut)).context(format!(
+                    "CGI program {} didn't finish in time",
+                    program.display()
+                )),
+            );
+        }
+    };
+    // Closing stdout ends the response, but the program still only has until the deadline to exit
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                warn!("CGI program {} exited with {status}", program.display());
+            }
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!("CGI program {} didn't exit in time", program.display());
+            stop(&mut child);
+            break;
+        }
+        thread::sleep(EXIT_POLL);
+    }
+    Ok(output)
+}
+
+fn stop(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Turns the header lines and body written by a CGI program into a response
+fn parse_output(output: Vec<u8>) -> Result<RouteResponse> {
+    let mut reader = Cursor::new(output);
+    let mut headers = Headers::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("CGI output ended before the end of its headers"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        headers.append_line(line)?;
+    }
+
+    let code = if let Some(status) = headers.get("Status") {
+        status
+            .split_whitespace()
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("Invalid CGI Status header: {status}"))
+            .and_then(ResponseCode::try_from)?
+    } else if headers.contains("Location") {
+        ResponseCode::Found
+    } else {
+        ResponseCode::Ok
+    };
+    for name in [
+        "Status",
+        "Content-Length",
+        "Transfer-Encoding",
+        "Connection",
+    ] {
+        headers.remove(name);
+    }
+
+    let position = usize::try_from(reader.position())?;
+    let mut body = reader.into_inner();
+    body.drain(..position);
+    let length = body.len() as u64;
+    let mut response = RouteResponse::from(("", code));
+    for (name, value) in headers.iter() {
+        response.headers_mut().append(name, value);
+    }
+    Ok(response.with_stream(BodyStream::new(Cursor::new(body), Some(length))))
+}
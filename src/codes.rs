@@ -4,30 +4,30 @@ use derive_more::derive::Display;
 #[allow(non_camel_case_types, dead_code)]
 #[derive(Display, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ResponseCode {
-    //100,
-    //101,
+    Continue = 100,
+    Switching_Protocols = 101,
     //102,
     //103,
     
     Ok = 200,
-    //201,
-    //202,
-    //203,
-    //204,
-    //205,
-    //206,
-    //207,
-    //226,
+    Created = 201,
+    Accepted = 202,
+    Non_Authoritative_Information = 203,
+    No_Content = 204,
+    Reset_Content = 205,
+    Partial_Content = 206,
+    Multi_Status = 207,
+    IM_Used = 226,
 
-    //300,
-    //301,
-    //302,
-    //303,
-    //304,
+    Multiple_Choices = 300,
+    Moved_Permanently = 301,
+    Found = 302,
+    See_Other = 303,
+    Not_Modified = 304,
     //305,
     //306,
-    //307,
-    //308,
+    Temporary_Redirect = 307,
+    Permanent_Redirect = 308,
 
     Bad_Request = 400,
     Unauthorized = 401,
@@ -41,23 +41,23 @@ pub enum ResponseCode {
     Conflict = 409,
     Gone = 410,
     Length_Required = 411,
-    //412,
-    //413,
-    //414,
-    //415,
-    //416,
-    //417,
+    Precondition_Failed = 412,
+    Content_Too_Large = 413,
+    URI_Too_Long = 414,
+    Unsupported_Media_Type = 415,
+    Range_Not_Satisfiable = 416,
+    Expectation_Failed = 417,
     //418,
-    //421,
-    //422,
-    //423,
-    //424,
-    //425,
-    //426,
-    //428,
-    //429,
-    //431,
-    //451,
+    Misdirected_Request = 421,
+    Unprocessable_Content = 422,
+    Locked = 423,
+    Failed_Dependency = 424,
+    Too_Early = 425,
+    Upgrade_Required = 426,
+    Precondition_Required = 428,
+    Too_Many_Requests = 429,
+    Request_Header_Fields_Too_Large = 431,
+    Unavailable_For_Legal_Reasons = 451,
 
     Internal_Server_Error = 500,
     Not_Implemented = 501,
@@ -73,7 +73,93 @@ pub enum ResponseCode {
 }
 
 impl ResponseCode {
+    const ALL: &'static [Self] = &[
+        Self::Continue,
+        Self::Switching_Protocols,
+        Self::Ok,
+        Self::Created,
+        Self::Accepted,
+        Self::Non_Authoritative_Information,
+        Self::No_Content,
+        Self::Reset_Content,
+        Self::Partial_Content,
+        Self::Multi_Status,
+        Self::IM_Used,
+        Self::Multiple_Choices,
+        Self::Moved_Permanently,
+        Self::Found,
+        Self::See_Other,
+        Self::Not_Modified,
+        Self::Temporary_Redirect,
+        Self::Permanent_Redirect,
+        Self::Bad_Request,
+        Self::Unauthorized,
+        Self::Payment_Required,
+        Self::Forbidden,
+        Self::Not_Found,
+        Self::Method_Not_Allowed,
+        Self::Not_Acceptable,
+        Self::Proxy_Authentication_Required,
+        Self::Request_Timeout,
+        Self::Conflict,
+        Self::Gone,
+        Self::Length_Required,
+        Self::Precondition_Failed,
+        Self::Content_Too_Large,
+        Self::URI_Too_Long,
+        Self::Unsupported_Media_Type,
+        Self::Range_Not_Satisfiable,
+        Self::Expectation_Failed,
+        Self::Misdirected_Request,
+        Self::Unprocessable_Content,
+        Self::Locked,
+        Self::Failed_Dependency,
+        Self::Too_Early,
+        Self::Upgrade_Required,
+        Self::Precondition_Required,
+        Self::Too_Many_Requests,
+        Self::Request_Header_Fields_Too_Large,
+        Self::Unavailable_For_Legal_Reasons,
+        Self::Internal_Server_Error,
+        Self::Not_Implemented,
+        Self::Bad_Gateway,
+        Self::Service_Unavailable,
+        Self::Gateway_Timeout,
+        Self::HTTP_Version_Not_Supported,
+        Self::Variant_Also_Negotiates,
+        Self::Insufficient_Storage,
+        Self::Loop_Detected,
+        Self::Not_Extended,
+        Self::Network_Authentication_Required,
+    ];
+
     pub fn pretty_string(self) -> String {
-        format!("{} {}", self as i32, self.to_string().replace('_', " "))
+        format!("{} {}", self as i32, self.reason())
+    }
+
+    /// The reason phrase, ie: "Not Found"
+    pub fn reason(self) -> String {
+        self.to_string().replace('_', " ")
+    }
+
+    pub const fn is_redirect(self) -> bool {
+        matches!(self as i32, 300..=399)
+    }
+
+    /// Responses with these codes never have a body, not even an empty one
+    pub const fn allows_body(self) -> bool {
+        !matches!(self as i32, 100..=199 | 204 | 304)
+    }
+}
+
+impl TryFrom<u16> for ResponseCode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Self::ALL
+            .iter()
+            .find(|code| **code as i32 == i32::from(value))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unsupported response code: {value}"))
     }
 }
@@ -0,0 +1,657 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ahash::HashMap;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+
+use crate::{
+    balance::{Balance, BalanceOptions, HealthCheck},
+    cgi::{Backend, Cgi},
+    codes::ResponseCode,
+    cookie::is_token,
+    hosts::Hosts,
+    listener::{Address, ListenSpec},
+    proxy::Proxy,
+    request::Method,
+    route::{HandlerFn, Route, Routes},
+    session::{FileStore, MemoryStore, SessionOptions, SessionStore, Sessions},
+};
+
+/// Server configuration as loaded from a TOML file
+///
+/// Every field is optional so that an empty file is valid, anything missing falls back to the
+/// command line arguments or their defaults.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub runtime: RuntimeMode,
+    /// Pool threads, or worker threads in the async runtime, 0 picks a default
+    pub threads: Option<usize>,
+    pub shutdown_timeout: Option<u64>,
+    /// Same format as `--listen`
+    pub listen: Vec<ListenSpec>,
+    pub tls: Option<TlsConfig>,
+    pub limits: Limits,
+    pub logging: Logging,
+    /// The routes for any host that isn't matched by one of `hosts`
+    pub site: Site,
+    pub hosts: Vec<HostConfig>,
+}
+
+/// How connections are served
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeMode {
+    /// Every connection occupies a pool thread for as long as it's open
+    #[default]
+    Threads,
+    /// Connections are served by an async runtime, so idle and waiting connections are cheap
+    Async,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Same format as `--sni`
+    pub sni: Vec<String>,
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The largest request body we'll accept, in bytes. Multipart bodies sent to handlers over
+    /// HTTP/1.1 are streamed to them instead, and limited by their `MultipartLimits`
+    pub max_body_size: usize,
+    /// The largest request head, the start-line and headers together, in bytes. Larger ones are
+    /// answered with a 431
+    pub max_header_size: usize,
+    /// Seconds an idle keep-alive connection is held open waiting for another request
+    pub keep_alive_timeout: u64,
+    /// Seconds a client has to finish sending a request once it has started
+    pub request_timeout: u64,
+    /// Connections allowed to wait for a pool thread before new ones are turned away with a 503,
+    /// 0 for no limit. The async runtime has no queue, so there it limits open connections
+    pub max_pending_connections: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_body_size: 10 * 1024 * 1024,
+            max_header_size: 64 * 1024,
+            keep_alive_timeout: 5,
+            request_timeout: 30,
+            max_pending_connections: 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub const fn keep_alive_timeout(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_timeout)
+    }
+
+    pub const fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// One of off, error, warn, info, debug or trace
+    pub level: String,
+    pub compact: bool,
+    pub ansi: bool,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            level: String::from("info"),
+            compact: false,
+            ansi: true,
+        }
+    }
+}
+
+impl Logging {
+    pub fn level(&self) -> Result<LevelFilter> {
+        self.level
+            .parse()
+            .map_err(|_| anyhow!("logging.level: invalid level '{}'", self.level))
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Site {
+    pub static_dir: Option<String>,
+    pub auto_index: bool,
+    /// Seconds a handler may take before the client gets a 504, routes can set their own
+    pub handler_timeout: Option<u64>,
+    /// Files to serve in place of the built in error responses, keyed by status code
+    pub error_pages: HashMap<String, String>,
+    pub sessions: Option<SessionConfig>,
+    pub routes: Vec<RouteConfig>,
+}
+
+/// Cookie-backed sessions for every route on the site
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub store: SessionStoreKind,
+    /// Where the file store keeps its sessions
+    pub dir: Option<String>,
+    pub cookie_name: String,
+    /// Seconds a session lasts without being used
+    pub idle_timeout: u64,
+    /// Seconds a session lasts from when it was created, however much it's used
+    pub absolute_timeout: u64,
+    /// Only send the cookie over HTTPS
+    pub secure: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        let options = SessionOptions::default();
+        Self {
+            store: SessionStoreKind::default(),
+            dir: None,
+            cookie_name: options.cookie_name,
+            idle_timeout: options.idle_timeout.as_secs(),
+            absolute_timeout: options.absolute_timeout.as_secs(),
+            secure: options.secure,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    /// Sessions are lost when the server stops, reloading the config keeps them
+    #[default]
+    Memory,
+    /// Each session is a file in `dir`
+    File,
+}
+
+impl SessionConfig {
+    fn build(&self, key: &str, store: Arc<dyn SessionStore>) -> Result<Sessions> {
+        if !is_token(&self.cookie_name) {
+            return Err(anyhow!(
+                "{key}.cookie_name: must be a token, ie: \"session\""
+            ));
+        }
+        if self.idle_timeout == 0 || self.absolute_timeout == 0 {
+            return Err(anyhow!("{key}: timeouts must be at least a second"));
+        }
+        Ok(Sessions::new(
+            store,
+            SessionOptions {
+                cookie_name: self.cookie_name.clone(),
+                idle_timeout: Duration::from_secs(self.idle_timeout),
+                absolute_timeout: Duration::from_secs(self.absolute_timeout),
+                secure: self.secure,
+            },
+        ))
+    }
+
+    fn build_store(&self, key: &str) -> Result<Arc<dyn SessionStore>> {
+        match (self.store, &self.dir) {
+            (SessionStoreKind::Memory, None) => Ok(Arc::new(MemoryStore::default())),
+            (SessionStoreKind::Memory, Some(_)) => {
+                Err(anyhow!("{key}.dir: only allowed with the file store"))
+            }
+            (SessionStoreKind::File, Some(dir)) => Ok(Arc::new(
+                FileStore::new(dir).with_context(|| format!("{key}.dir: unusable '{dir}'"))?,
+            )),
+            (SessionStoreKind::File, None) => {
+                Err(anyhow!("{key}.dir: required for the file store"))
+            }
+        }
+    }
+}
+
+/// The session stores from the last time the config was built, so that reloading it doesn't end
+/// every session unless a site's store settings have changed
+#[derive(Debug, Default)]
+pub struct SessionStores {
+    kept: Mutex<HashMap<String, KeptStore>>,
+}
+
+#[derive(Debug, Clone)]
+struct KeptStore {
+    /// The store and dir it was built with
+    settings: (SessionStoreKind, Option<String>),
+    store: Arc<dyn SessionStore>,
+}
+
+impl SessionStores {
+    /// The store kept for the site if its settings are the same, otherwise a new one. Either way
+    /// it's added to `built`, which replaces the kept stores once the whole config is valid
+    fn get_or_build(
+        &self,
+        site: &str,
+        config: &SessionConfig,
+        key: &str,
+        built: &mut HashMap<String, KeptStore>,
+    ) -> Result<Arc<dyn SessionStore>> {
+        let settings = (config.store, config.dir.clone());
+        let kept = self
+            .kept
+            .lock()
+            .unwrap()
+            .get(site)
+            .filter(|kept| kept.settings == settings)
+            .map(|kept| kept.store.clone());
+        let store = match kept {
+            Some(store) => store,
+            None => config.build_store(key)?,
+        };
+        built.insert(
+            site.to_string(),
+            KeptStore {
+                settings,
+                store: store.clone(),
+            },
+        );
+        Ok(store)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// Host names this site is served for, may include wildcards such as `*.example.com`
+    pub names: Vec<String>,
+    #[serde(flatten)]
+    pub site: Site,
+}
+
+/// A single route, exactly one of `static`, `plain`, `redirect`, `proxy`, `cgi`, `fastcgi` or
+/// `handler` must be provided
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub target: String,
+    #[serde(rename = "static")]
+    pub static_file: Option<String>,
+    pub plain: Option<String>,
+    pub redirect: Option<String>,
+    /// One or more `http://` URLs that the target, and everything below it, is forwarded to
+    pub proxy: Option<Upstreams>,
+    /// How requests are spread across the proxy's upstreams, defaults to `round_robin`
+    pub balance: Option<Balance>,
+    /// Consecutive failed requests before an upstream is ejected, 0 disables ejection
+    pub max_fails: Option<u32>,
+    /// Seconds an ejected upstream is left out of rotation
+    pub fail_timeout: Option<u64>,
+    pub health_check: Option<HealthCheckConfig>,
+    /// A CGI program that's run for the target, and everything below it
+    pub cgi: Option<String>,
+    /// A FastCGI server, `host:port` or `unix:/path`, that the target, and everything below it, is
+    /// sent to
+    pub fastcgi: Option<String>,
+    /// The script the FastCGI server should run, passed on as `SCRIPT_FILENAME`
+    pub script: Option<String>,
+    /// The name of a handler built into the server
+    pub handler: Option<String>,
+    /// Methods the handler accepts, defaults to GET
+    pub methods: Option<Vec<String>>,
+    /// Seconds the handler may take, overriding the site's `handler_timeout`. For a proxy, how long
+    /// the upstream has to connect and to send each part of its response, and for CGI, how long
+    /// the program or server has to respond
+    pub timeout: Option<u64>,
+    pub code: Option<u16>,
+}
+
+/// A proxy's upstreams, either a single URL or a list of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Upstreams {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Upstreams {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            Self::One(url) => std::slice::from_ref(url),
+            Self::Many(urls) => urls,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Requested with a GET, upstreams must answer with a 2xx or 3xx to stay in rotation
+    pub path: String,
+    /// Seconds between checks
+    #[serde(default = "HealthCheckConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl HealthCheckConfig {
+    const fn default_interval() -> u64 {
+        10
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))
+    }
+
+    /// Builds every host from the config, checking that everything it refers to exists
+    ///
+    /// Dynamic routes can't be expressed in TOML, so they refer to one of the provided handlers by
+    /// name instead
+    ///
+    /// Session stores are taken from `stores` when a site's store settings haven't changed
+    pub fn build_hosts(
+        &self,
+        handlers: &HashMap<&str, HandlerFn>,
+        stores: &SessionStores,
+    ) -> Result<Hosts> {
+        let mut built = HashMap::default();
+        let mut hosts = Hosts::new(
+            self.site
+                .build_routes("site", "", handlers, stores, &mut built)?,
+        );
+        for (index, host) in self.hosts.iter().enumerate() {
+            let key = format!("hosts[{index}]");
+            if host.names.is_empty() {
+                return Err(anyhow!("{key}.names: at least one host name is required"));
+            }
+            let routes = host.site.build_routes(
+                &key,
+                &host.names.join(","),
+                handlers,
+                stores,
+                &mut built,
+            )?;
+            for name in &host.names {
+                hosts
+                    .add_host(name, routes.clone())
+                    .with_context(|| format!("{key}.names: invalid host '{name}'"))?;
+            }
+        }
+        *stores.kept.lock().unwrap() = built;
+        Ok(hosts)
+    }
+}
+
+impl Site {
+    /// `site` identifies the site across reloads, so it can keep its session store
+    fn build_routes(
+        &self,
+        key: &str,
+        site: &str,
+        handlers: &HashMap<&str, HandlerFn>,
+        stores: &SessionStores,
+        built: &mut HashMap<String, KeptStore>,
+    ) -> Result<Routes> {
+        let mut routes = Routes::default();
+        routes.set_auto_index(self.auto_index);
+        routes.set_handler_timeout(self.handler_timeout.map(Duration::from_secs));
+        if let Some(dir) = &self.static_dir {
+            if !Path::new(dir).is_dir() {
+                return Err(anyhow!("{key}.static_dir: '{dir}' is not a directory"));
+            }
+            routes.set_static_dir(dir);
+        }
+        if let Some(sessions) = &self.sessions {
+            let key = format!("{key}.sessions");
+            let store = stores.get_or_build(site, sessions, &key, built)?;
+            routes.set_sessions(sessions.build(&key, store)?);
+        }
+
+        for (code, page) in &self.error_pages {
+            let key = format!("{key}.error_pages.{code}");
+            check_file(&key, page)?;
+            let route = Route::Static(page.clone(), Some(parse_code(&key, code)?));
+            match code.as_str() {
+                "404" => routes.set_404(route),
+                "405" => routes.set_405(route),
+                _ => return Err(anyhow!("{key}: only 404 and 405 pages are supported")),
+            }
+        }
+
+        for (index, route) in self.routes.iter().enumerate() {
+            let key = format!("{key}.routes[{index}]");
+            route.add_to(&mut routes, &key, handlers)?;
+        }
+        Ok(routes)
+    }
+}
+
+impl RouteConfig {
+    fn add_to(
+        &self,
+        routes: &mut Routes,
+        key: &str,
+        handlers: &HashMap<&str, HandlerFn>,
+    ) -> Result<()> {
+        if !self.target.starts_with('/') {
+            return Err(anyhow!("{key}.target: must start with '/'"));
+        }
+        let code = self
+            .code
+            .map(|code| parse_code(&format!("{key}.code"), &code.to_string()))
+            .transpose()?;
+        self.check_options(key)?;
+        // Adding a route only fails if the target is already taken
+        let in_use = || format!("{key}.target: '{}' is already in use", self.target);
+        let timeout = self.timeout.map(Duration::from_secs);
+
+        let kinds = [
+            self.static_file.is_some(),
+            self.plain.is_some(),
+            self.redirect.is_some(),
+            self.proxy.is_some(),
+            self.cgi.is_some(),
+            self.fastcgi.is_some(),
+            self.handler.is_some(),
+        ];
+        if kinds.into_iter().filter(|set| *set).count() != 1 {
+            return Err(anyhow!(
+                "{key}: exactly one of static, plain, redirect, proxy, cgi, fastcgi or handler is required"
+            ));
+        }
+
+        if let Some(path) = &self.static_file {
+            check_file(&format!("{key}.static"), path)?;
+            routes
+                .add_static(&self.target, path, code)
+                .with_context(in_use)
+        } else if let Some(content) = &self.plain {
+            routes
+                .add_plain(&self.target, content, code)
+                .with_context(in_use)
+        } else if let Some(location) = &self.redirect {
+            if code.is_some_and(|code| !code.is_redirect()) {
+                return Err(anyhow!("{key}.code: must be a redirect code"));
+            }
+            routes
+                .add_redirect(&self.target, location, code)
+                .with_context(in_use)
+        } else if code.is_some() {
+            Err(anyhow!(
+                "{key}.code: only allowed along with static, plain or redirect"
+            ))
+        } else if let Some(upstream) = &self.proxy {
+            let proxy = Proxy::new(
+                &self.target,
+                upstream.as_slice(),
+                timeout,
+                self.balance_options(key)?,
+            )
+            .with_context(|| format!("{key}.proxy: invalid upstream"))?;
+            routes.add_proxy(proxy).with_context(in_use)
+        } else if let Some(program) = &self.cgi {
+            check_file(&format!("{key}.cgi"), program)?;
+            // Programs run from their own directory, so a relative path would no longer work
+            let program = fs::canonicalize(program)
+                .with_context(|| format!("{key}.cgi: failed to resolve '{program}'"))?;
+            let backend = Backend::Program(program);
+            routes
+                .add_cgi(Cgi::new(&self.target, backend, timeout))
+                .with_context(in_use)
+        } else if let Some(address) = &self.fastcgi {
+            let backend = Backend::FastCgi {
+                address: parse_fastcgi_address(&format!("{key}.fastcgi"), address)?,
+                script: self.script.as_ref().map(PathBuf::from),
+            };
+            routes
+                .add_cgi(Cgi::new(&self.target, backend, timeout))
+                .with_context(in_use)
+        } else {
+            self.add_handler_to(routes, key, handlers)
+        }
+    }
+
+    fn add_handler_to(
+        &self,
+        routes: &mut Routes,
+        key: &str,
+        handlers: &HashMap<&str, HandlerFn>,
+    ) -> Result<()> {
+        let name = self.handler.as_deref().unwrap_or_default();
+        let handler = handlers
+            .get(name)
+            .with_context(|| format!("{key}.handler: unknown handler '{name}'"))?;
+        let methods = self
+            .methods
+            .as_deref()
+            .unwrap_or(&[])
+            .iter()
+            .map(|method| {
+                method
+                    .to_ascii_uppercase()
+                    .parse::<Method>()
+                    .map_err(|_| anyhow!("{key}.methods: unknown method '{method}'"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let methods = if methods.is_empty() {
+            vec![Method::GET]
+        } else {
+            methods
+        };
+        routes
+            .add_handler(
+                &self.target,
+                methods,
+                handler.clone(),
+                self.timeout.map(Duration::from_secs),
+            )
+            .with_context(|| format!("{key}.target: '{}' is already in use", self.target))
+    }
+
+    /// Checks that options only used by some kinds of route aren't set on the others
+    fn check_options(&self, key: &str) -> Result<()> {
+        let proxy = self.proxy.is_some();
+        let fastcgi = self.fastcgi.is_some();
+        let takes_timeout = self.handler.is_some() || proxy || self.cgi.is_some() || fastcgi;
+        let set = [
+            (
+                "methods",
+                self.methods.is_some(),
+                self.handler.is_some(),
+                "handler",
+            ),
+            (
+                "timeout",
+                self.timeout.is_some(),
+                takes_timeout,
+                "handler, proxy, cgi or fastcgi",
+            ),
+            ("balance", self.balance.is_some(), proxy, "proxy"),
+            ("max_fails", self.max_fails.is_some(), proxy, "proxy"),
+            ("fail_timeout", self.fail_timeout.is_some(), proxy, "proxy"),
+            ("health_check", self.health_check.is_some(), proxy, "proxy"),
+            ("script", self.script.is_some(), fastcgi, "fastcgi"),
+        ];
+        set.iter()
+            .find(|(_, set, allowed, _)| *set && !allowed)
+            .map_or(Ok(()), |(name, _, _, along_with)| {
+                Err(anyhow!(
+                    "{key}.{name}: only allowed along with {along_with}"
+                ))
+            })
+    }
+
+    fn balance_options(&self, key: &str) -> Result<BalanceOptions> {
+        let defaults = BalanceOptions::default();
+        let health_check = self
+            .health_check
+            .as_ref()
+            .map(|check| {
+                if !check.path.starts_with('/') {
+                    return Err(anyhow!("{key}.health_check.path: must start with '/'"));
+                }
+                if check.interval == 0 {
+                    return Err(anyhow!("{key}.health_check.interval: must be at least 1"));
+                }
+                Ok(HealthCheck {
+                    path: check.path.clone(),
+                    interval: Duration::from_secs(check.interval),
+                })
+            })
+            .transpose()?;
+        Ok(BalanceOptions {
+            balance: self.balance.unwrap_or(defaults.balance),
+            max_fails: self.max_fails.unwrap_or(defaults.max_fails),
+            fail_timeout: self
+                .fail_timeout
+                .map_or(defaults.fail_timeout, Duration::from_secs),
+            health_check,
+        })
+    }
+}
+
+/// `host:port` or `unix:/path`
+fn parse_fastcgi_address(key: &str, address: &str) -> Result<Address> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(anyhow!("{key}: unix socket path must not be empty"));
+        }
+        Ok(Address::Unix(PathBuf::from(path)))
+    } else if address.contains(':') {
+        Ok(Address::Tcp(address.to_string()))
+    } else {
+        Err(anyhow!(
+            "{key}: expected host:port or unix:/path, got '{address}'"
+        ))
+    }
+}
+
+fn check_file(key: &str, path: &str) -> Result<()> {
+    if Path::new(path).is_file() {
+        Ok(())
+    } else {
+        Err(anyhow!("{key}: '{path}' is not a file"))
+    }
+}
+
+fn parse_code(key: &str, code: &str) -> Result<ResponseCode> {
+    code.parse::<u16>()
+        .map_err(anyhow::Error::from)
+        .and_then(ResponseCode::try_from)
+        .with_context(|| format!("{key}: invalid response code '{code}'"))
+}
@@ -0,0 +1,653 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader as AsyncBufReader,
+    },
+    runtime::Handle,
+    time::timeout,
+};
+use tracing::{error, warn};
+
+use crate::{
+    body::{FramedBody, LentBody},
+    codes::ResponseCode,
+    config::Limits,
+    http2,
+    request::{Request, RequestError},
+    response,
+    route::{blocking_runtime, RouteFuture, RouteResponse, Upgrade},
+    shutdown::Shutdown,
+    stream::{AsyncStream, BufferedStream, Rewind, Stream, SyncBridge},
+};
+
+/// How often an idle connection checks if the server is shutting down
+const SHUTDOWN_POLL: Duration = Duration::from_millis(250);
+/// Seconds an overloaded server asks clients to wait before trying again
+const RETRY_AFTER: u64 = 5;
+/// How much of a streamed body the async runtime reads at a time
+const STREAM_BUFFER_SIZE: usize = 16 * 1024;
+/// How long we'll spend telling a client we're overloaded, TLS clients need a handshake first
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Answers requests on connections served by the thread pool
+pub trait Handler: Send + Sync {
+    fn apply(&self, request: &Request) -> Result<RouteResponse>;
+
+    /// Looks at a request before its body is read, when the client is waiting on `100 Continue`,
+    /// returning the response to turn it away with. Everything is let through by default
+    fn check(&self, _request: &Request) -> Result<Option<RouteResponse>> {
+        Ok(None)
+    }
+
+    /// Whether the request's body should be left on the connection for the handler to read as it
+    /// arrives. Every body is read up front by default
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+}
+
+impl<F: Fn(&Request) -> Result<RouteResponse> + Send + Sync> Handler for F {
+    fn apply(&self, request: &Request) -> Result<RouteResponse> {
+        self(request)
+    }
+}
+
+/// Answers requests on connections served by the async runtime
+pub trait AsyncHandler: Send + Sync {
+    fn apply(&self, request: Request) -> RouteFuture;
+
+    /// The async equivalent of `Handler::check`
+    fn check(&self, _request: &Request) -> Result<Option<RouteResponse>> {
+        Ok(None)
+    }
+
+    /// The async equivalent of `Handler::streams_body`
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+}
+
+impl<F: Fn(Request) -> RouteFuture + Send + Sync> AsyncHandler for F {
+    fn apply(&self, request: Request) -> RouteFuture {
+        self(request)
+    }
+}
+
+/// Serves requests on the connection until either side wants it closed
+///
+/// Connections that start with the HTTP/2 preface, whether that's h2c or h2 negotiated with ALPN,
+/// are handed to the HTTP/2 server on the async handlers' runtime
+pub fn handle_connection(
+    stream: Stream,
+    shutdown: &Arc<Shutdown>,
+    limits: Limits,
+    handler: &Arc<dyn Handler>,
+) {
+    let _guard = shutdown.track_connection();
+    let (peer_addr, secure) = (stream.peer_addr(), stream.is_secure());
+    let source_addr =
+        peer_addr.map_or_else(|| String::from("Unix Socket"), |addr| addr.to_string());
+    let mut reader = BufReader::new(stream);
+
+    let mut first = true;
+    loop {
+        match wait_for_request(&mut reader, shutdown, limits.keep_alive_timeout()) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                error!("Failed to read from connection with error: {err}");
+                break;
+            }
+        }
+        if mem::take(&mut first) && http2::is_preface(reader.buffer()) {
+            serve_http2(reader, shutdown, limits, handler);
+            return;
+        }
+        if let Err(err) = reader
+            .get_ref()
+            .set_read_timeout(Some(limits.request_timeout()))
+        {
+            error!("Failed to set read timeout with error: {err}");
+            break;
+        }
+
+        let request = read_request(&mut reader, limits, peer_addr, secure, &**handler);
+        let (request, route_response) = match request {
+            Ok(Incoming::Request(request)) => {
+                //tracing::debug!("Received Request:\n{}", &request.as_string());
+                let route_response = handler
+                    .apply(&request)
+                    .unwrap_or_else(|err| handler_failed(&request, &err));
+                (Some(request), route_response)
+            }
+            Ok(Incoming::Streamed(mut request, length)) => {
+                let body = LentBody::new(reader, length);
+                request.set_body_reader(body.reader());
+                let route_response = handler
+                    .apply(&request)
+                    .unwrap_or_else(|err| handler_failed(&request, &err));
+                reader = body.take_back();
+                (Some(request), route_response)
+            }
+            // The body is still waiting to be sent, so the connection can't be used again
+            Ok(Incoming::Rejected(route_response)) => (None, route_response),
+            Err(err) => (None, parse_failed(&err)),
+        };
+
+        let mut response =
+            finish_response(route_response, request.as_ref(), shutdown, &source_addr);
+        let (keep_alive, upgrade) = (response.keep_alive, response.upgrade.take());
+        if let Err(err) = write_response(reader.get_mut(), response) {
+            error!("Failed to write response with error: {err}");
+            return;
+        }
+        shutdown.request_served();
+        if let Some(upgrade) = upgrade {
+            // The connection belongs to the new protocol now, which is responsible for closing it
+            if let Err(err) = reader.get_ref().set_read_timeout(None) {
+                error!("Failed to clear read timeout with error: {err}");
+                return;
+            }
+            upgrade(Box::new(BufferedStream(reader)));
+            return;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+
+    if let Err(err) = reader.get_mut().finish() {
+        error!("Failed to close connection with error: {err}");
+    }
+}
+
+/// What reading a request turned up
+enum Incoming {
+    Request(Request),
+    /// The body, of the given length, is left on the connection for the handler to read
+    Streamed(Request, u64),
+    /// The client was waiting on `100 Continue` and has been turned away without sending its body
+    Rejected(RouteResponse),
+}
+
+/// Reads the head and then the body, clients waiting on `100 Continue` are only sent it once the
+/// head has passed the size and route checks, otherwise they're answered without the body being
+/// read
+fn read_request(
+    reader: &mut BufReader<Stream>,
+    limits: Limits,
+    peer_addr: Option<SocketAddr>,
+    secure: bool,
+    handler: &dyn Handler,
+) -> Result<Incoming> {
+    let mut request = Request::parse_head(&mut *reader, limits.max_header_size)?;
+    request.set_connection(peer_addr, secure);
+    let expects_continue = request.expects_continue()?;
+    let streamed = handler.streams_body(&request);
+    if let Some(length) = request.body_length(body_limit(streamed, limits))? {
+        if expects_continue {
+            if let Some(route_response) = handler.check(&request)? {
+                return Ok(Incoming::Rejected(route_response));
+            }
+            let stream = reader.get_mut();
+            stream.write_all(continue_line().as_bytes())?;
+            stream.flush()?;
+        }
+        if streamed {
+            return Ok(Incoming::Streamed(request, length as u64));
+        }
+        let mut body = vec![0; length];
+        reader
+            .read_exact(&mut body)
+            .context("Failed to read request body")?;
+        request.set_body(body);
+    }
+    Ok(Incoming::Request(request))
+}
+
+/// Streamed bodies are limited by the handler reading them instead
+const fn body_limit(streamed: bool, limits: Limits) -> usize {
+    if streamed {
+        usize::MAX
+    } else {
+        limits.max_body_size
+    }
+}
+
+/// The interim response telling a client to go ahead and send its body
+fn continue_line() -> String {
+    format!(
+        "{}\r\n\r\n",
+        response::StatusLine::new(ResponseCode::Continue, None)
+    )
+}
+
+/// Moves the connection onto the runtime that runs async handlers, sync handlers are run on its
+/// blocking threads so requests on the connection don't have to wait for each other
+fn serve_http2(
+    reader: BufReader<Stream>,
+    shutdown: &Arc<Shutdown>,
+    limits: Limits,
+    handler: &Arc<dyn Handler>,
+) {
+    let buffered = reader.buffer().to_vec();
+    let stream = reader.into_inner();
+    let (peer_addr, secure) = (stream.peer_addr(), stream.is_secure());
+    let handler: Arc<dyn AsyncHandler> = {
+        let handler = handler.clone();
+        Arc::new(move |request: Request| -> RouteFuture {
+            let handler = handler.clone();
+            Box::pin(async move {
+                tokio::task::spawn_blocking(move || handler.apply(&request))
+                    .await
+                    .context("Handler panicked")?
+            })
+        })
+    };
+    blocking_runtime().block_on(async {
+        match stream.into_async() {
+            Ok(stream) => {
+                let io = Rewind::new(buffered, stream);
+                http2::serve(io, peer_addr, secure, shutdown, limits, &handler).await;
+            }
+            Err(err) => error!("Failed to move connection to the async runtime with error: {err}"),
+        }
+    });
+}
+
+/// Turns a connection away with a 503 without reading its request, as the server is overloaded
+pub fn reject_connection(mut stream: Stream, shutdown: &Shutdown) {
+    shutdown.connection_rejected();
+    let response = overloaded_response(shutdown);
+    let written = stream
+        .set_read_timeout(Some(REJECT_TIMEOUT))
+        .and_then(|()| write_response(&mut stream, response))
+        .and_then(|()| stream.finish());
+    if let Err(err) = written {
+        error!("Failed to reject connection with error: {err}");
+    }
+}
+
+/// The async equivalent of `reject_connection`
+pub async fn reject_connection_async(mut stream: AsyncStream, shutdown: &Shutdown) {
+    shutdown.connection_rejected();
+    let response = overloaded_response(shutdown);
+    let written = timeout(REJECT_TIMEOUT, async {
+        write_response_async(&mut stream, response).await?;
+        stream.shutdown().await
+    })
+    .await
+    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+    if let Err(err) = written {
+        error!("Failed to reject connection with error: {err}");
+    }
+}
+
+fn overloaded_response(shutdown: &Shutdown) -> Outgoing {
+    let route_response =
+        RouteResponse::from(("Service Unavailable", ResponseCode::Service_Unavailable))
+            .with_header("Retry-After", RETRY_AFTER.to_string());
+    finish_response(route_response, None, shutdown, "")
+}
+
+/// Waits for the client to start sending a request
+///
+/// Returns false if the client closed the connection, went idle for too long, or the server
+/// started shutting down before anything arrived
+fn wait_for_request(
+    reader: &mut BufReader<Stream>,
+    shutdown: &Shutdown,
+    keep_alive_timeout: Duration,
+) -> io::Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true);
+    }
+    reader.get_ref().set_read_timeout(Some(SHUTDOWN_POLL))?;
+    let idle = Instant::now();
+    loop {
+        match reader.fill_buf() {
+            Ok(buf) => return Ok(!buf.is_empty()),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if shutdown.is_triggered() || idle.elapsed() >= keep_alive_timeout {
+                    return Ok(false);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Serves requests on an async connection until either side wants it closed
+pub async fn handle_connection_async(
+    stream: AsyncStream,
+    shutdown: &Arc<Shutdown>,
+    limits: Limits,
+    handler: &Arc<dyn AsyncHandler>,
+) {
+    let _guard = shutdown.track_connection();
+    let (peer_addr, secure) = (stream.peer_addr(), stream.is_secure());
+    let source_addr =
+        peer_addr.map_or_else(|| String::from("Unix Socket"), |addr| addr.to_string());
+    let mut reader = AsyncBufReader::new(stream);
+
+    let mut first = true;
+    loop {
+        match wait_for_request_async(&mut reader, shutdown, limits.keep_alive_timeout()).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                error!("Failed to read from connection with error: {err}");
+                break;
+            }
+        }
+        if mem::take(&mut first) && http2::is_preface(reader.buffer()) {
+            // The reader passes writes straight through, and keeps what it's already buffered
+            http2::serve(reader, peer_addr, secure, shutdown, limits, handler).await;
+            return;
+        }
+
+        let request = timeout(
+            limits.request_timeout(),
+            read_request_async(&mut reader, limits, peer_addr, secure, &**handler),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out reading request")));
+        let (request, route_response) = match request {
+            Ok(Incoming::Request(request)) => {
+                let route_response = handler
+                    .apply(request.clone())
+                    .await
+                    .unwrap_or_else(|err| handler_failed(&request, &err));
+                (Some(request), route_response)
+            }
+            // Handlers read the body with blocking I/O, which the bridge runs on this runtime
+            Ok(Incoming::Streamed(mut request, length)) => {
+                let bridge = SyncBridge::new(reader, Handle::current())
+                    .with_read_timeout(limits.request_timeout());
+                let body = LentBody::new(bridge, length);
+                request.set_body_reader(body.reader());
+                let route_response = handler
+                    .apply(request.clone())
+                    .await
+                    .unwrap_or_else(|err| handler_failed(&request, &err));
+                reader = body.take_back().into_inner();
+                (Some(request), route_response)
+            }
+            Ok(Incoming::Rejected(route_response)) => (None, route_response),
+            Err(err) => (None, parse_failed(&err)),
+        };
+
+        let mut response =
+            finish_response(route_response, request.as_ref(), shutdown, &source_addr);
+        let (keep_alive, upgrade) = (response.keep_alive, response.upgrade.take());
+        if let Err(err) = write_response_async(reader.get_mut(), response).await {
+            error!("Failed to write response with error: {err}");
+            return;
+        }
+        shutdown.request_served();
+        if let Some(upgrade) = upgrade {
+            // Upgraded protocols use blocking I/O, so they're kept off the runtime's workers
+            let socket = SyncBridge::new(reader, Handle::current());
+            if let Err(err) = tokio::task::spawn_blocking(move || upgrade(Box::new(socket))).await {
+                error!("Upgraded connection failed with error: {err}");
+            }
+            return;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+
+    if let Err(err) = reader.get_mut().shutdown().await {
+        error!("Failed to close connection with error: {err}");
+    }
+}
+
+/// The async equivalent of `wait_for_request`
+async fn wait_for_request_async<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    shutdown: &Shutdown,
+    keep_alive_timeout: Duration,
+) -> io::Result<bool> {
+    let idle = Instant::now();
+    loop {
+        // Nothing is lost when this times out, read data only moves into the buffer on completion
+        if let Ok(buf) = timeout(SHUTDOWN_POLL, reader.fill_buf()).await {
+            return Ok(!buf?.is_empty());
+        }
+        if shutdown.is_triggered() || idle.elapsed() >= keep_alive_timeout {
+            return Ok(false);
+        }
+    }
+}
+
+/// Reads the head a line at a time, so that it can be handed to the same parser as the sync
+/// connections, followed by the body once it's passed the same checks
+async fn read_request_async<S: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut AsyncBufReader<S>,
+    limits: Limits,
+    peer_addr: Option<SocketAddr>,
+    secure: bool,
+    handler: &dyn AsyncHandler,
+) -> Result<Incoming> {
+    let mut head = String::new();
+    let mut limited = (&mut *reader).take(limits.max_header_size as u64);
+    loop {
+        let start = head.len();
+        let read = limited.read_line(&mut head).await?;
+        if limited.limit() == 0 && !head.ends_with('\n') {
+            return Err(RequestError::HeadersTooLarge {
+                limit: limits.max_header_size,
+            }
+            .into());
+        }
+        if read == 0 {
+            return Err(anyhow!("Connection closed before end of headers"));
+        }
+        if head[start..].trim_end_matches(['\r', '\n']).is_empty() {
+            break;
+        }
+    }
+
+    let mut request = Request::parse_head(head.as_bytes(), limits.max_header_size)?;
+    request.set_connection(peer_addr, secure);
+    let expects_continue = request.expects_continue()?;
+    let streamed = handler.streams_body(&request);
+    if let Some(length) = request.body_length(body_limit(streamed, limits))? {
+        if expects_continue {
+            if let Some(route_response) = handler.check(&request)? {
+                return Ok(Incoming::Rejected(route_response));
+            }
+            let stream = reader.get_mut();
+            stream.write_all(continue_line().as_bytes()).await?;
+            stream.flush().await?;
+        }
+        if streamed {
+            return Ok(Incoming::Streamed(request, length as u64));
+        }
+        let mut body = vec![0; length];
+        reader
+            .read_exact(&mut body)
+            .await
+            .context("Failed to read request body")?;
+        request.set_body(body);
+    }
+    Ok(Incoming::Request(request))
+}
+
+pub fn parse_failed(err: &anyhow::Error) -> RouteResponse {
+    error!("Failed to parse Request with error: {err}");
+    let code = err
+        .downcast_ref::<RequestError>()
+        .map_or(ResponseCode::Bad_Request, RequestError::code);
+    ("Failed to parse", code).into()
+}
+
+/// The response for a route that returned an error rather than a response, ie: a static file
+/// that's gone missing
+pub fn handler_failed(request: &Request, err: &anyhow::Error) -> RouteResponse {
+    error!(
+        "Handler for {} {} failed with error: {err:#}",
+        request.method(),
+        request.target()
+    );
+    RouteResponse::from(("Internal Server Error", ResponseCode::Internal_Server_Error))
+}
+
+/// A response ready to be written to the connection
+struct Outgoing {
+    /// The status line and headers, followed by the body unless it's streamed
+    head: String,
+    body: Option<FramedBody>,
+    keep_alive: bool,
+    upgrade: Option<Upgrade>,
+}
+
+/// Logs the response if the route asked for it and serializes it, working out whether the
+/// connection can be kept open for another request
+fn finish_response(
+    mut route_response: RouteResponse,
+    request: Option<&Request>,
+    shutdown: &Shutdown,
+    source_addr: &str,
+) -> Outgoing {
+    if route_response.should_log() {
+        log_response(&route_response, request, source_addr);
+    }
+
+    // HTTP/1.0 clients can't read chunked bodies, so a body of unknown length is sent as it is
+    // and ends when the connection closes
+    let stream = route_response.take_stream();
+    let legacy = request.is_some_and(|request| request.version() == "HTTP/1.0");
+    let close_delimited = legacy
+        && route_response.code().allows_body()
+        && stream
+            .as_ref()
+            .is_some_and(|stream| stream.length().is_none());
+
+    // A request we failed to parse may have left part of itself in the stream, so we can't
+    // trust anything that comes after it
+    let upgrade = route_response.take_upgrade();
+    let keep_alive = upgrade.is_none()
+        && !close_delimited
+        && request.is_some_and(|request| request.wants_keep_alive() && !request.has_unread_body())
+        && !shutdown.is_triggered();
+    if keep_alive && legacy {
+        route_response
+            .headers_mut()
+            .insert("Connection", "keep-alive");
+    } else if !keep_alive && upgrade.is_none() {
+        route_response.headers_mut().insert("Connection", "close");
+    }
+
+    // We decide how the body is framed, so the route doesn't get a say in the framing headers
+    let headers = route_response.headers_mut();
+    headers.remove("Transfer-Encoding");
+    headers.remove("Content-Length");
+    let allows_body = route_response.code().allows_body();
+    let (content, body) = match stream {
+        _ if !allows_body => ("", None),
+        Some(stream) if close_delimited => ("", Some(stream.unframed())),
+        Some(stream) => {
+            let headers = route_response.headers_mut();
+            match stream.length() {
+                Some(length) => headers.insert("Content-Length", length.to_string()),
+                None => headers.insert("Transfer-Encoding", "chunked"),
+            }
+            ("", Some(stream.framed()))
+        }
+        None => {
+            let content_length = route_response.content().len().to_string();
+            route_response
+                .headers_mut()
+                .insert("Content-Length", content_length);
+            (route_response.content(), None)
+        }
+    };
+
+    let head = format!(
+        "{}\r\n{}\r\n{content}",
+        response::StatusLine::new(
+            route_response.code(),
+            request.map(|request| request.version().as_str())
+        ),
+        route_response.headers(),
+    );
+    Outgoing {
+        head,
+        body,
+        keep_alive,
+        upgrade,
+    }
+}
+
+pub fn log_response(route_response: &RouteResponse, request: Option<&Request>, source_addr: &str) {
+    if let Some(context) = route_response.context() {
+        warn!(
+            r"Route Requested logging with context: {context}
+            Source Address: {source_addr}
+            Request Body: {}",
+            request.map_or_else(|| "None".into(), Request::as_string)
+        );
+    } else {
+        warn!(
+            r"Route Requested logging
+            Source Address: {source_addr}
+            Request Body: {}",
+            request.map_or_else(|| "None".into(), Request::as_string)
+        );
+    }
+}
+
+fn write_response(stream: &mut Stream, response: Outgoing) -> io::Result<()> {
+    stream.write_all(response.head.as_bytes())?;
+    if let Some(mut body) = response.body {
+        let mut buf = vec![0; STREAM_BUFFER_SIZE];
+        loop {
+            let read = body.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&buf[..read])?;
+            // Streamed bodies can be slow to produce, like event streams, so each part is sent
+            // as soon as it's read
+            stream.flush()?;
+        }
+    }
+    stream.flush()
+}
+
+async fn write_response_async<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    response: Outgoing,
+) -> io::Result<()> {
+    stream.write_all(response.head.as_bytes()).await?;
+    if let Some(mut body) = response.body {
+        let mut buf = vec![0; STREAM_BUFFER_SIZE];
+        loop {
+            // Streamed bodies are read with blocking I/O
+            let read = tokio::task::block_in_place(|| body.read(&mut buf))?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&buf[..read]).await?;
+            stream.flush().await?;
+        }
+    }
+    stream.flush().await
+}
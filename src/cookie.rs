@@ -0,0 +1,416 @@
+use std::{
+    fmt::{self, Debug, Display},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use derive_more::derive::Display;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use urlencoding::{decode, encode};
+
+use crate::headers::Headers;
+
+/// Length of a base64 encoded HMAC-SHA256 tag, which signed values start with
+const SIGNATURE_LENGTH: usize = 43;
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The cookies the client sent, from every `Cookie` header
+///
+/// Values are percent decoded, as that's how `Cookie` writes anything that isn't allowed in a
+/// cookie as it is
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>,
+}
+
+#[allow(dead_code)]
+impl CookieJar {
+    pub fn parse(headers: &Headers) -> Self {
+        let cookies = headers
+            .get_all("Cookie")
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                let value = decode(value).map_or_else(|_| value.to_string(), Into::into);
+                Some((name.to_string(), value))
+            })
+            .collect();
+        Self { cookies }
+    }
+
+    /// The value of the first cookie with the name, clients send the most specific one first
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The value of a cookie set with `Cookie::signed`, if its signature is valid
+    pub fn get_signed(&self, name: &str, key: &Key) -> Option<String> {
+        key.verify(name, self.get(name)?)
+    }
+
+    /// The value of a cookie set with `Cookie::encrypted`, if it can be decrypted
+    pub fn get_encrypted(&self, name: &str, key: &Key) -> Option<String> {
+        key.decrypt(name, self.get(name)?)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.cookies
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+}
+
+/// Whether the cookie is sent along with requests from other sites
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this on secure cookies, so it also sets `Secure`
+    None,
+}
+
+/// A cookie to send with `Set-Cookie`
+///
+/// Anything in the value that isn't allowed in a cookie is percent encoded, which `CookieJar`
+/// decodes again
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+#[allow(dead_code)]
+impl Cookie {
+    /// The name is sent as it is, so it has to be a token, see `validate`
+    pub fn new<A: Into<String>, B: Into<String>>(name: A, value: B) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Tells the client to delete the cookie, the path and domain have to match the ones it was
+    /// set with
+    pub fn removal<S: Into<String>>(name: S) -> Self {
+        Self::new(name, "")
+            .with_expires(UNIX_EPOCH)
+            .with_max_age(Duration::ZERO)
+    }
+
+    /// Signs the value so the client can read it but not change it, the name is part of the
+    /// signature so a value can't be moved to another cookie
+    #[must_use]
+    pub fn signed(mut self, key: &Key) -> Self {
+        self.value = key.sign(&self.name, &self.value);
+        self
+    }
+
+    /// Encrypts the value so the client can neither read nor change it
+    pub fn encrypted(mut self, key: &Key) -> Result<Self> {
+        self.value = key.encrypt(&self.name, &self.value)?;
+        Ok(self)
+    }
+
+    #[must_use]
+    pub fn with_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    #[must_use]
+    pub fn with_domain<S: Into<String>>(mut self, domain: S) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Clients that support `Max-Age` use it instead
+    #[must_use]
+    pub const fn with_expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only sent over HTTPS
+    #[must_use]
+    pub const fn with_secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// Hidden from scripts running in the page
+    #[must_use]
+    pub const fn with_http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks the name is a token and the path and domain can't end their attribute, or the
+    /// header, early. `RouteResponse::with_cookie` refuses cookies that fail this
+    pub fn validate(&self) -> Result<()> {
+        if !is_token(&self.name) {
+            return Err(anyhow!("Invalid cookie name: {:?}", self.name));
+        }
+        for (attribute, value) in [("Path", &self.path), ("Domain", &self.domain)] {
+            if let Some(value) = value {
+                if !value
+                    .bytes()
+                    .all(|byte| (0x20..0x7f).contains(&byte) && byte != b';')
+                {
+                    return Err(anyhow!("Invalid cookie {attribute}: {value:?}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+/// The value of a `Set-Cookie` header
+impl Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, encode(&self.value))?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={same_site}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the name is a token as defined in RFC 9110, which is what a cookie name has to be
+pub fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Formats the time as an IMF-fixdate, ie: "Sun, 06 Nov 1994 08:49:37 GMT"
+fn http_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+    // Converts days since the epoch to a date, see http://howardhinnant.github.io/date_algorithms.html
+    let shifted = days + 719_468;
+    let (era, day_of_era) = (shifted / 146_097, shifted % 146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[usize::try_from(days % 7).unwrap_or_default()],
+        MONTHS[usize::try_from(month - 1).unwrap_or_default()],
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
+
+/// The server's secret for signed and encrypted cookies
+///
+/// Cookies only stay valid for as long as the key does, so one that's loaded rather than
+/// generated is needed for them to survive a restart
+#[derive(Clone)]
+pub struct Key {
+    signing: hmac::Key,
+    encryption: [u8; 32],
+}
+
+#[allow(dead_code)]
+impl Key {
+    /// The shortest secret that will be accepted
+    pub const MIN_SECRET_LENGTH: usize = 32;
+
+    /// Derives separate keys for signing and encryption from the secret
+    pub fn from_secret(secret: &[u8]) -> Result<Self> {
+        if secret.len() < Self::MIN_SECRET_LENGTH {
+            return Err(anyhow!(
+                "Cookie key must be at least {} bytes",
+                Self::MIN_SECRET_LENGTH
+            ));
+        }
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(secret);
+        let derive = |info: &[u8]| {
+            let mut key = [0; 32];
+            prk.expand(&[info], KeyLength(key.len()))
+                .and_then(|okm| okm.fill(&mut key))
+                .map_err(|_| anyhow!("Failed to derive cookie key"))?;
+            anyhow::Ok(key)
+        };
+        Ok(Self {
+            signing: hmac::Key::new(hmac::HMAC_SHA256, &derive(b"cookie signing")?),
+            encryption: derive(b"cookie encryption")?,
+        })
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .context("Cookie key is not valid base64")?;
+        Self::from_secret(&secret)
+    }
+
+    /// A random key, which only lasts until the server restarts
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0; 64];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| anyhow!("Failed to generate cookie key"))?;
+        Self::from_secret(&secret)
+    }
+
+    fn sign(&self, name: &str, value: &str) -> String {
+        let tag = hmac::sign(&self.signing, format!("{name}={value}").as_bytes());
+        format!("{}{value}", URL_SAFE_NO_PAD.encode(tag))
+    }
+
+    fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        if !signed.is_char_boundary(SIGNATURE_LENGTH) {
+            return None;
+        }
+        let (tag, value) = signed.split_at(SIGNATURE_LENGTH);
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.signing, format!("{name}={value}").as_bytes(), &tag).ok()?;
+        Some(value.to_string())
+    }
+
+    fn cipher(&self) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &self.encryption).expect("AES-256 keys are 32 bytes"),
+        )
+    }
+
+    /// The nonce is random for every value, and sent in front of the ciphertext
+    fn encrypt(&self, name: &str, value: &str) -> Result<String> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Failed to generate cookie nonce"))?;
+        let mut sealed = value.as_bytes().to_vec();
+        self.cipher()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt cookie"))?;
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&sealed);
+        Ok(URL_SAFE_NO_PAD.encode(out))
+    }
+
+    fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let value = self
+            .cipher()
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed)
+            .ok()?;
+        String::from_utf8(value.to_vec()).ok()
+    }
+}
+
+/// Keys are never printed
+impl Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key { .. }")
+    }
+}
+
+/// How much key material HKDF is asked for
+struct KeyLength(usize);
+
+impl hkdf::KeyType for KeyLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
@@ -0,0 +1,254 @@
+use std::{any::Any, fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    codes::ResponseCode,
+    cookie::CookieJar,
+    headers::Headers,
+    request::{Request, RequestError},
+    route::RouteResponse,
+    session::Session,
+};
+
+/// What extractors are given to pull their values from
+pub struct Context<'a> {
+    request: &'a Request,
+    state: Option<&'a (dyn Any + Send + Sync)>,
+}
+
+#[allow(dead_code)]
+impl Context<'_> {
+    pub const fn request(&self) -> &Request {
+        self.request
+    }
+}
+
+/// A handler parameter that's built from the request before the handler is called
+pub trait FromRequest: Sized {
+    fn from_request(context: &Context) -> Result<Self, Rejection>;
+}
+
+/// Why an extractor couldn't build its value, which is sent to the client as a JSON error
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    code: ResponseCode,
+    message: String,
+}
+
+impl Rejection {
+    pub fn new<S: Into<String>>(code: ResponseCode, message: S) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Rejection> for RouteResponse {
+    fn from(rejection: Rejection) -> Self {
+        Self::json_error(rejection.code, &rejection.message)
+    }
+}
+
+/// The parameters captured by `{name}` segments in the route's target, `T` is a struct with a
+/// field for each of them
+#[derive(Debug, Clone)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        // The parameters were decoded along with the target, so they're encoded again to be
+        // parsed the same way as a query string, which turns them into numbers and the like
+        serde_urlencoded::to_string(context.request.path_params())
+            .map_err(|err| err.to_string())
+            .and_then(|params| serde_urlencoded::from_str(&params).map_err(|err| err.to_string()))
+            .map(Path)
+            .map_err(|err| {
+                Rejection::new(
+                    ResponseCode::Bad_Request,
+                    format!("Invalid path parameters: {err}"),
+                )
+            })
+    }
+}
+
+/// The query string, `T` is a struct with a field for each parameter
+#[derive(Debug, Clone)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        let query = context.request.query().map_or("", String::as_str);
+        serde_urlencoded::from_str(query).map(Query).map_err(|err| {
+            Rejection::new(
+                ResponseCode::Bad_Request,
+                format!("Invalid query string: {err}"),
+            )
+        })
+    }
+}
+
+/// A JSON body
+///
+/// Bodies that aren't JSON get a 415, ones that can't be parsed a 400 and ones that don't fit `T`
+/// a 422
+#[derive(Debug, Clone)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        context.request.json().map(Json).map_err(|err| {
+            let code = err.downcast_ref::<RequestError>().map_or_else(
+                || {
+                    let unfit = err
+                        .downcast_ref::<serde_json::Error>()
+                        .is_some_and(serde_json::Error::is_data);
+                    if unfit {
+                        ResponseCode::Unprocessable_Content
+                    } else {
+                        ResponseCode::Bad_Request
+                    }
+                },
+                RequestError::code,
+            );
+            Rejection::new(code, format!("{err:#}"))
+        })
+    }
+}
+
+/// The state the handler was registered with, see `TypedFn::with_state`
+#[derive(Debug, Clone)]
+pub struct State<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        context
+            .state
+            .and_then(|state| state.downcast_ref::<T>())
+            .cloned()
+            .map(State)
+            .ok_or_else(|| {
+                Rejection::new(
+                    ResponseCode::Internal_Server_Error,
+                    format!(
+                        "Handler wasn't given state of type {}",
+                        std::any::type_name::<T>()
+                    ),
+                )
+            })
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        Ok(context.request.headers().clone())
+    }
+}
+
+impl FromRequest for CookieJar {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        Ok(context.request.cookies())
+    }
+}
+
+/// The client's session, a 500 if the site doesn't have sessions enabled
+impl FromRequest for Session {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        context.request.session().cloned().ok_or_else(|| {
+            Rejection::new(
+                ResponseCode::Internal_Server_Error,
+                "Sessions aren't enabled for this site",
+            )
+        })
+    }
+}
+
+/// The request itself, for anything the other extractors don't cover
+impl FromRequest for Request {
+    fn from_request(context: &Context) -> Result<Self, Rejection> {
+        Ok(context.request.clone())
+    }
+}
+
+/// A function whose parameters can all be extracted from the request, `Args` is only there to
+/// tell the implementations for each number of parameters apart
+pub trait ExtractHandler<Args>: Send + Sync + 'static {
+    fn call(&self, context: &Context) -> Result<RouteResponse>;
+}
+
+macro_rules! impl_extract_handler {
+    ($($arg:ident),+) => {
+        impl<H, $($arg: FromRequest),+> ExtractHandler<($($arg,)+)> for H
+        where
+            H: Fn($($arg),+) -> Result<RouteResponse> + Send + Sync + 'static,
+        {
+            #[allow(non_snake_case)]
+            fn call(&self, context: &Context) -> Result<RouteResponse> {
+                $(
+                    let $arg = match $arg::from_request(context) {
+                        Ok(value) => value,
+                        Err(rejection) => return Ok(rejection.into()),
+                    };
+                )+
+                self($($arg),+)
+            }
+        }
+    };
+}
+
+impl_extract_handler!(A);
+impl_extract_handler!(A, B);
+impl_extract_handler!(A, B, C);
+impl_extract_handler!(A, B, C, D);
+impl_extract_handler!(A, B, C, D, E);
+impl_extract_handler!(A, B, C, D, E, F);
+
+type ErasedFn = dyn Fn(&Request) -> Result<RouteResponse> + Send + Sync;
+
+/// A handler with typed parameters, each extracted from the request before it's called, so
+/// handlers don't have to parse the request themselves
+#[derive(Clone)]
+pub struct TypedFn {
+    call: Arc<ErasedFn>,
+}
+
+#[allow(dead_code)]
+impl TypedFn {
+    pub fn new<Args, H: ExtractHandler<Args>>(handler: H) -> Self {
+        Self::build(handler, None)
+    }
+
+    /// Gives the handler `state` to extract with `State`, which is shared by every request
+    pub fn with_state<Args, H: ExtractHandler<Args>, S: Send + Sync + 'static>(
+        handler: H,
+        state: S,
+    ) -> Self {
+        Self::build(handler, Some(Arc::new(state)))
+    }
+
+    fn build<Args, H: ExtractHandler<Args>>(
+        handler: H,
+        state: Option<Arc<dyn Any + Send + Sync>>,
+    ) -> Self {
+        Self {
+            call: Arc::new(move |request| {
+                handler.call(&Context {
+                    request,
+                    state: state.as_deref(),
+                })
+            }),
+        }
+    }
+
+    pub fn call(&self, request: &Request) -> Result<RouteResponse> {
+        (self.call)(request)
+    }
+}
+
+impl Debug for TypedFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TypedFn")
+    }
+}
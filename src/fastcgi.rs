@@ -0,0 +1,207 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::warn;
+
+use crate::{listener::Address, stream::Stream};
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// Each connection only carries one request, so they can all use the same id
+const REQUEST_ID: u16 = 1;
+/// The most content a single record can hold
+const MAX_CONTENT: usize = u16::MAX as usize;
+
+/// Sends a request to a FastCGI responder, returning everything it wrote to stdout
+///
+/// The whole exchange has to be done by the deadline, and the server can't write more than
+/// `max_output` bytes to stdout. The connection is closed after the request, anything written to
+/// stderr is logged
+pub fn run(
+    address: &Address,
+    params: &[(String, String)],
+    body: &[u8],
+    deadline: Instant,
+    max_output: usize,
+) -> Result<Vec<u8>> {
+    let mut stream = Deadline {
+        stream: connect(address, remaining(deadline)?)?,
+        deadline,
+    };
+
+    let mut out = Vec::new();
+    // Role, then flags, with keep-conn unset so the server closes the connection when it's done
+    let [role_high, role_low] = RESPONDER.to_be_bytes();
+    write_record(
+        &mut out,
+        BEGIN_REQUEST,
+        &[role_high, role_low, 0, 0, 0, 0, 0, 0],
+    );
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    write_stream(&mut out, PARAMS, &encoded);
+    write_stream(&mut out, STDIN, body);
+    stream
+        .write_all(&out)
+        .and_then(|()| stream.flush())
+        .context("Failed to send request to the FastCGI server")?;
+
+    let mut stdout = Vec::new();
+    loop {
+        let mut header = [0; 8];
+        stream
+            .read_exact(&mut header)
+            .context("FastCGI server closed the connection before ending the request")?;
+        let content_length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let mut content = vec![0; content_length + usize::from(header[6])];
+        stream
+            .read_exact(&mut content)
+            .context("Failed to read from the FastCGI server")?;
+        content.truncate(content_length);
+        match header[1] {
+            STDOUT if stdout.len() + content.len() > max_output => {
+                return Err(anyhow!(
+                    "FastCGI server {address} wrote more than {max_output} bytes"
+                ));
+            }
+            STDOUT => stdout.extend_from_slice(&content),
+            STDERR => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    warn!("FastCGI server {address} wrote to stderr: {line}");
+                }
+            }
+            END_REQUEST => {
+                // The protocol status follows the 4 byte application status
+                return match content.get(4) {
+                    Some(0) => Ok(stdout),
+                    Some(status) => Err(anyhow!(
+                        "FastCGI server {address} refused the request with status {status}"
+                    )),
+                    None => Err(anyhow!("FastCGI server {address} sent an invalid end")),
+                };
+            }
+            // Management records aren't requested, so anything else can be ignored
+            _ => {}
+        }
+    }
+}
+
+/// What's left of the time until the deadline, an error once it's passed
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or_else(|| io::ErrorKind::TimedOut.into())
+}
+
+/// A connection that fails once the deadline has passed, however slowly the data trickles in
+struct Deadline {
+    stream: Stream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream
+            .set_read_timeout(Some(remaining(self.deadline)?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream
+            .set_write_timeout(Some(remaining(self.deadline)?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn connect(address: &Address, timeout: Duration) -> Result<Stream> {
+    match address {
+        Address::Tcp(address) => {
+            let mut last_err = None;
+            for addr in address
+                .to_socket_addrs()
+                .with_context(|| format!("Failed to resolve FastCGI server {address}"))?
+            {
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(stream) => return Ok(Stream::Tcp(stream)),
+                    Err(err) => last_err = Some(err),
+                }
+            }
+            Err(last_err.map_or_else(
+                || anyhow!("FastCGI server {address} resolved to no addresses"),
+                |err| {
+                    anyhow::Error::new(err)
+                        .context(format!("Failed to connect to FastCGI server {address}"))
+                },
+            ))
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => UnixStream::connect(path)
+            .map(Stream::Unix)
+            .with_context(|| format!("Failed to connect to FastCGI server {}", path.display())),
+        #[cfg(not(unix))]
+        Address::Unix(_) => Err(anyhow!("Unix sockets are not supported on this platform")),
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let [length_high, length_low] = u16::try_from(content.len())
+        .expect("Records are split to fit")
+        .to_be_bytes();
+    let [id_high, id_low] = REQUEST_ID.to_be_bytes();
+    out.extend_from_slice(&[
+        VERSION,
+        kind,
+        id_high,
+        id_low,
+        length_high,
+        length_low,
+        0,
+        0,
+    ]);
+    out.extend_from_slice(content);
+}
+
+/// Writes the content as records of a stream, which is ended by an empty record
+fn write_stream(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    for chunk in content.chunks(MAX_CONTENT) {
+        write_record(out, kind, chunk);
+    }
+    write_record(out, kind, &[]);
+}
+
+/// Name and value lengths take a single byte when they're short enough, otherwise 4 with the top
+/// bit set
+fn encode_length(out: &mut Vec<u8>, length: usize) {
+    match u8::try_from(length) {
+        Ok(length) if length < 0x80 => out.push(length),
+        _ => {
+            let length = u32::try_from(length).unwrap_or(u32::MAX >> 1) | 0x8000_0000;
+            out.extend_from_slice(&length.to_be_bytes());
+        }
+    }
+}
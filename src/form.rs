@@ -0,0 +1,360 @@
+use std::{
+    io::{self, Read, Write},
+    mem,
+    path::Path,
+};
+
+use ahash::HashMap;
+use anyhow::{anyhow, Context, Result};
+use tempfile::NamedTempFile;
+
+use crate::{headers::Headers, request::RequestError};
+
+/// How much of a multipart body is read from its source at a time
+const READ_SIZE: usize = 16 * 1024;
+/// The most header data a single part can have
+const MAX_PART_HEADER_SIZE: usize = 8 * 1024;
+/// RFC 2046 doesn't allow boundaries any longer than this
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+/// The fields of an `application/x-www-form-urlencoded` body, or a query string, where a name can
+/// have several values
+#[derive(Debug, Clone, Default)]
+pub struct Form {
+    fields: HashMap<String, Vec<String>>,
+}
+
+#[allow(dead_code)]
+impl Form {
+    /// Names and values are percent decoded, with '+' standing in for a space
+    pub fn parse(encoded: &str) -> Result<Self> {
+        let mut form = Self::default();
+        for pair in encoded.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            form.fields
+                .entry(decode_component(name)?)
+                .or_default()
+                .push(decode_component(value)?);
+        }
+        Ok(form)
+    }
+
+    /// The first value sent for the field
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).first().map(String::as_str)
+    }
+
+    /// Every value sent for the field, in the order they were sent
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.fields.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fields.contains_key(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.fields
+            .iter()
+            .map(|(name, values)| (name.as_str(), values.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+fn decode_component(component: &str) -> Result<String> {
+    Ok(urlencoding::decode(&component.replace('+', " "))
+        .context("Form field is not valid UTF-8")?
+        .into_owned())
+}
+
+/// How much of a multipart body will be read before it's rejected with a 413
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartLimits {
+    /// The most a single field or file can hold
+    pub max_part_size: usize,
+    /// The most the whole body can hold, including the boundaries and headers of each part
+    pub max_total_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: 16 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A single part of a `multipart/form-data` body
+#[derive(Debug)]
+pub enum Part {
+    Field { name: String, value: String },
+    File(FileUpload),
+}
+
+/// A file from a multipart body, held in a temp file that's removed when this is dropped unless
+/// it's persisted
+#[derive(Debug)]
+pub struct FileUpload {
+    name: String,
+    filename: String,
+    content_type: Option<String>,
+    size: usize,
+    file: NamedTempFile,
+}
+
+#[allow(dead_code)]
+impl FileUpload {
+    /// The name of the form field the file was sent for
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file name as the client sent it, which shouldn't be trusted as a path
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Where the upload is stored until it's dropped
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Opens the upload to be read from the start
+    pub fn open(&self) -> io::Result<std::fs::File> {
+        self.file.reopen()
+    }
+
+    /// Moves the upload to `path` so it's kept, which has to be on the same file system as the
+    /// temp dir
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        self.file
+            .persist(path)
+            .map_err(|err| err.error)
+            .context("Failed to keep file upload")?;
+        Ok(())
+    }
+}
+
+/// Reads a `multipart/form-data` body one part at a time, so that files are written to disk as
+/// they're read rather than held in memory
+pub struct Multipart<R> {
+    reader: R,
+    /// CRLF, "--" and the boundary, which is what ends every part
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    limits: MultipartLimits,
+    total: usize,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Nothing has been read yet, anything before the first boundary is ignored
+    Preamble,
+    /// A boundary has just been read, it's followed by another part or the end of the body
+    Boundary,
+    Done,
+}
+
+#[allow(dead_code)]
+impl<R: Read> Multipart<R> {
+    pub fn new(reader: R, boundary: &str, limits: MultipartLimits) -> Result<Self> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LENGTH {
+            return Err(anyhow!("Invalid multipart boundary: {boundary}"));
+        }
+        Ok(Self {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The first boundary doesn't have to follow a line break, this lets it be found the
+            // same way as the rest
+            buf: b"\r\n".to_vec(),
+            limits,
+            total: 0,
+            state: State::Preamble,
+        })
+    }
+
+    /// Reads the next part, returning `None` once the closing boundary has been read
+    ///
+    /// Parts or bodies over their limits are reported as a `RequestError`, so they can be turned
+    /// into a 413
+    pub fn next_part(&mut self) -> Result<Option<Part>> {
+        match self.state {
+            State::Done => return Ok(None),
+            State::Preamble => {
+                self.copy_until_delimiter(&mut io::sink(), usize::MAX)?;
+                self.state = State::Boundary;
+            }
+            State::Boundary => {}
+        }
+        // The closing boundary has "--" straight after it, otherwise the rest of the line is
+        // padding that's ignored
+        if self.read_line()?.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+
+        let mut headers = Headers::new();
+        let mut header_size = 0;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            header_size += line.len();
+            if header_size > MAX_PART_HEADER_SIZE {
+                return Err(anyhow!("Multipart part headers are too large"));
+            }
+            headers.append_line(
+                std::str::from_utf8(&line).context("Multipart part header is not valid UTF-8")?,
+            )?;
+        }
+        let disposition = headers
+            .get("Content-Disposition")
+            .context("Multipart part is missing its Content-Disposition")?;
+        let (kind, params) = parse_disposition(disposition);
+        if !kind.eq_ignore_ascii_case("form-data") {
+            return Err(anyhow!("Multipart part isn't form-data: {kind}"));
+        }
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        let name = param("name").context("Multipart part is missing its name")?;
+
+        let limit = self.limits.max_part_size;
+        let too_large = |name: String| RequestError::PartTooLarge { name, limit };
+        let part = if let Some(filename) = param("filename") {
+            let mut file = NamedTempFile::new().context("Failed to create temp file for upload")?;
+            let Some(size) = self.copy_until_delimiter(&mut file, limit)? else {
+                return Err(too_large(name).into());
+            };
+            file.flush()?;
+            Part::File(FileUpload {
+                name,
+                filename,
+                content_type: headers.get("Content-Type").map(String::from),
+                size,
+                file,
+            })
+        } else {
+            let mut value = Vec::new();
+            if self.copy_until_delimiter(&mut value, limit)?.is_none() {
+                return Err(too_large(name).into());
+            }
+            let value = String::from_utf8(value)
+                .with_context(|| format!("Multipart field {name} is not valid UTF-8"))?;
+            Part::Field { name, value }
+        };
+        Ok(Some(part))
+    }
+
+    /// Reads more of the body into the buffer, returning false if there's nothing left
+    fn fill(&mut self) -> Result<bool> {
+        let mut chunk = vec![0; READ_SIZE];
+        let read = self.reader.read(&mut chunk)?;
+        self.total += read;
+        if self.total > self.limits.max_total_size {
+            return Err(RequestError::MultipartTooLarge {
+                limit: self.limits.max_total_size,
+            }
+            .into());
+        }
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    /// Reads a line of the part's head, without its CRLF
+    fn read_line(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(index) = find(&self.buf, b"\r\n") {
+                let mut line: Vec<u8> = self.buf.drain(..index + 2).collect();
+                line.truncate(index);
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_PART_HEADER_SIZE {
+                return Err(anyhow!("Multipart part headers are too large"));
+            }
+            if !self.fill()? {
+                return Err(anyhow!("Multipart body ended in the middle of a part"));
+            }
+        }
+    }
+
+    /// Copies the body to `out` up to the next boundary, which is consumed, returning how much
+    /// was copied or `None` if it would've gone over `limit`
+    fn copy_until_delimiter<W: Write>(
+        &mut self,
+        out: &mut W,
+        limit: usize,
+    ) -> Result<Option<usize>> {
+        let mut copied = 0;
+        loop {
+            let found = find(&self.buf, &self.delimiter);
+            // Without a boundary, the end of the buffer is held back as it may be the start of one
+            let length =
+                found.unwrap_or_else(|| self.buf.len().saturating_sub(self.delimiter.len() - 1));
+            copied += length;
+            if copied > limit {
+                return Ok(None);
+            }
+            out.write_all(&self.buf[..length])?;
+            if found.is_some() {
+                self.buf.drain(..length + self.delimiter.len());
+                return Ok(Some(copied));
+            }
+            self.buf.drain(..length);
+            if !self.fill()? {
+                return Err(anyhow!("Multipart body ended before its closing boundary"));
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Splits a `Content-Disposition` value into its type and parameters
+///
+/// Browsers percent encode quotes in file names rather than escaping them, so a backslash is
+/// taken as it is
+fn parse_disposition(value: &str) -> (String, Vec<(String, String)>) {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => segments.push(mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    segments.push(current);
+
+    let mut segments = segments.into_iter();
+    let kind = segments.next().unwrap_or_default().trim().to_string();
+    let params = segments
+        .filter_map(|segment| {
+            let (name, value) = segment.split_once('=')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    (kind, params)
+}
@@ -0,0 +1,116 @@
+use std::{env, os::fd::OwnedFd, process::Command, sync::Arc, thread, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use command_fds::{CommandFdExt, FdMapping};
+use listenfd::ListenFd;
+use signal_hook::{consts::SIGUSR2, iterator::Signals};
+use tracing::{error, info};
+
+use crate::{
+    listener::{Address, Listener},
+    shutdown::Shutdown,
+};
+
+/// The first file descriptor used by the systemd socket activation protocol
+const LISTEN_FDS_START: i32 = 3;
+/// How long a new process has to fail before we consider it successfully started
+const STARTUP_GRACE: Duration = Duration::from_secs(1);
+
+/// Listening sockets passed to us by systemd socket activation, or a previous process restarting
+///
+/// Sockets are matched to the configured listeners by position, so they must be provided in the
+/// same order as the listeners are configured in
+pub struct Inherited(ListenFd);
+
+impl Inherited {
+    pub fn from_env() -> Option<Self> {
+        let fds = ListenFd::from_env();
+        (fds.len() > 0).then_some(Self(fds))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn take(&mut self, index: usize, address: &Address) -> Result<Listener> {
+        let listener = match address {
+            Address::Tcp(_) => self.0.take_tcp_listener(index)?.map(Listener::Tcp),
+            Address::Unix(_) => self
+                .0
+                .take_unix_listener(index)?
+                .map(Listener::inherit_unix)
+                .transpose()?,
+        };
+        listener.with_context(|| format!("Inherited socket {index} was already taken"))
+    }
+}
+
+/// Starts a new copy of this process that takes over the listening sockets
+///
+/// The new process is started with the same executable path and arguments, so replacing the
+/// binary on disk and then sending `SIGUSR2` upgrades the server without refusing any connections.
+/// Once the new process is up this one stops accepting and drains like a normal shutdown.
+pub struct Handoff {
+    fds: Vec<OwnedFd>,
+}
+
+impl Handoff {
+    pub fn new<'a, I: IntoIterator<Item = &'a Listener>>(listeners: I) -> Result<Self> {
+        let fds = listeners
+            .into_iter()
+            .map(Listener::try_clone_fd)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { fds })
+    }
+
+    pub fn listen_for_restart(self, shutdown: Arc<Shutdown>) -> Result<()> {
+        let mut signals = Signals::new([SIGUSR2])?;
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                if shutdown.is_triggered() {
+                    continue;
+                }
+                info!("Received SIGUSR2, restarting");
+                match self.spawn_successor() {
+                    Ok(()) => {
+                        shutdown.hand_off();
+                        break;
+                    }
+                    Err(err) => error!("Failed to restart, continuing to serve. Error: {err:#}"),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn spawn_successor(&self) -> Result<()> {
+        let mappings = self
+            .fds
+            .iter()
+            .zip(LISTEN_FDS_START..)
+            .map(|(fd, child_fd)| {
+                Ok(FdMapping {
+                    parent_fd: fd.try_clone()?,
+                    child_fd,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let mut child = Command::new(env::current_exe()?)
+            .args(env::args_os().skip(1))
+            .env("LISTEN_FDS", self.fds.len().to_string())
+            // We can't know the pid ahead of time, without it the sockets are accepted by any pid
+            .env_remove("LISTEN_PID")
+            .env_remove("LISTEN_FDNAMES")
+            .fd_mappings(mappings)?
+            .spawn()
+            .context("Failed to start new process")?;
+
+        thread::sleep(STARTUP_GRACE);
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!("New process exited during startup with {status}"));
+        }
+        info!("New process {} started, handing off", child.id());
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::Display;
+
+use anyhow::{anyhow, Context, Result};
+
+/// An ordered collection of HTTP headers
+///
+/// Header names keep the case they were received or inserted with, but every lookup is case
+/// insensitive. Repeated headers, like `Cookie` or `Accept`, are stored as separate entries in the
+/// order they were added instead of overwriting each other.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+#[allow(dead_code)]
+impl Headers {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Parses a single `name: value` header line and appends it
+    pub fn append_line(&mut self, line: &str) -> Result<()> {
+        let (name, value) = line.split_once(':').context("Failed to parse header")?;
+        if name.is_empty() || name.trim() != name {
+            // RFC 9112 does not allow whitespace between the header name and the colon
+            return Err(anyhow!("Invalid header name: {name:?}"));
+        }
+        self.append(name, value.trim());
+        Ok(())
+    }
+
+    /// Adds a value for the header without removing any existing values
+    pub fn append<A: Into<String>, B: Into<String>>(&mut self, name: A, value: B) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Replaces every existing value for the header with the provided value
+    pub fn insert<A: Into<String>, B: Into<String>>(&mut self, name: A, value: B) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Removes every value for the header, returning true if anything was removed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        len != self.entries.len()
+    }
+
+    /// Returns the first value for the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value for the header in the order they were added
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every comma separated element across all values for the header
+    ///
+    /// This is only correct for headers defined as comma separated lists, it should not be used
+    /// for things like `Set-Cookie` or `Date`
+    pub fn get_list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Returns true if the comma separated header contains the token, ignoring case
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name)
+            .any(|value| value.eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub const fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Parses the `Content-Length` header
+    ///
+    /// Returns an error if the header is present but invalid, or if multiple differing values were
+    /// provided, as either could be used for request smuggling
+    pub fn content_length(&self) -> Result<Option<usize>> {
+        let mut length = None;
+        for value in self.get_list("Content-Length") {
+            let value: usize = value.parse().context("Invalid Content-Length")?;
+            if length.is_some_and(|length| length != value) {
+                return Err(anyhow!("Conflicting Content-Length values"));
+            }
+            length = Some(value);
+        }
+        Ok(length)
+    }
+
+    pub fn content_type(&self) -> Option<MediaType> {
+        self.get("Content-Type").and_then(MediaType::parse)
+    }
+
+    /// Parses every `Accept` header into a list sorted from most to least preferred
+    ///
+    /// Entries with a quality of 0 are kept, as they explicitly mark a type as not acceptable
+    pub fn accept(&self) -> Vec<MediaRange> {
+        let mut ranges: Vec<MediaRange> = self
+            .get_list("Accept")
+            .filter_map(MediaRange::parse)
+            .collect();
+        // sort_by is stable, so equally preferred types keep the order the client sent them in
+        ranges.sort_by(|a, b| b.quality.total_cmp(&a.quality));
+        ranges
+    }
+
+    /// Returns true if the client will accept the provided media type
+    ///
+    /// A request without an `Accept` header accepts everything
+    pub fn accepts(&self, essence: &str) -> bool {
+        let ranges = self.accept();
+        if ranges.is_empty() {
+            return true;
+        }
+        // The most specific matching range is the one that applies
+        ranges
+            .iter()
+            .filter(|range| range.matches(essence))
+            .max_by_key(|range| range.specificity())
+            .is_some_and(|range| range.quality > 0.0)
+    }
+}
+
+impl Display for Headers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{name}: {value}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = std::iter::Map<
+        std::slice::Iter<'a, (String, String)>,
+        fn(&'a (String, String)) -> (&'a str, &'a str),
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// A media type such as `text/html; charset=utf-8`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaType {
+    essence: String,
+    params: Vec<(String, String)>,
+}
+
+#[allow(dead_code)]
+impl MediaType {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';');
+        let essence = parts.next()?.trim().to_ascii_lowercase();
+        let (kind, subtype) = essence.split_once('/')?;
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+        let params = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                Some((
+                    name.trim().to_ascii_lowercase(),
+                    value.trim().trim_matches('"').to_string(),
+                ))
+            })
+            .collect();
+        Some(Self { essence, params })
+    }
+
+    /// The lowercase `type/subtype` without any parameters
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.essence)?;
+        for (name, value) in &self.params {
+            write!(f, "; {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A single entry from an `Accept` header
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRange {
+    media: MediaType,
+    quality: f32,
+}
+
+#[allow(dead_code)]
+impl MediaRange {
+    fn parse(value: &str) -> Option<Self> {
+        let mut media = MediaType::parse(value)?;
+        let quality = media
+            .param("q")
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())?
+            .clamp(0.0, 1.0);
+        media.params.retain(|(name, _)| name != "q");
+        Some(Self { media, quality })
+    }
+
+    pub const fn media(&self) -> &MediaType {
+        &self.media
+    }
+
+    pub const fn quality(&self) -> f32 {
+        self.quality
+    }
+
+    /// Returns true if this range includes the provided `type/subtype`
+    pub fn matches(&self, essence: &str) -> bool {
+        let Some((kind, subtype)) = essence.split_once('/') else {
+            return false;
+        };
+        let Some((range_kind, range_subtype)) = self.media.essence.split_once('/') else {
+            return false;
+        };
+        (range_kind == "*" || range_kind.eq_ignore_ascii_case(kind))
+            && (range_subtype == "*" || range_subtype.eq_ignore_ascii_case(subtype))
+    }
+
+    fn specificity(&self) -> u8 {
+        match self.media.essence.as_str() {
+            "*/*" => 0,
+            essence if essence.ends_with("/*") => 1,
+            _ => 2,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use ahash::HashMap;
+use anyhow::{anyhow, Result};
+
+use crate::{
+    request::Request,
+    route::{RouteResponse, Routes},
+};
+
+/// Selects a set of [`Routes`] based on the host a request was made to
+///
+/// Hosts can be registered by exact name, `example.com`, or as a wildcard for every subdomain,
+/// `*.example.com`. An exact match always wins over a wildcard, and the most specific wildcard wins
+/// over any less specific one. Requests that don't match any host, or that don't provide one, are
+/// served by the default routes.
+#[derive(Default, Debug, Clone)]
+pub struct Hosts {
+    exact: HashMap<String, Routes>,
+    // Stored as the suffix including the leading '.', ie: '.example.com'
+    wildcard: Vec<(String, Routes)>,
+    default: Routes,
+}
+
+#[allow(dead_code)]
+impl Hosts {
+    pub fn new(default: Routes) -> Self {
+        Self {
+            default,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_host<A: AsRef<str>>(&mut self, pattern: A, routes: Routes) -> Result<()> {
+        let pattern = normalize(pattern.as_ref());
+        if pattern.is_empty() || pattern == "*" {
+            return Err(anyhow!(
+                "Use set_default to set the routes for unmatched hosts"
+            ));
+        }
+        if let Some(suffix) = pattern.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 || suffix.contains('*') {
+                return Err(anyhow!("Invalid wildcard host: {pattern}"));
+            }
+            if self.wildcard.iter().any(|(existing, _)| existing == suffix) {
+                return Err(anyhow!("Host already exists"));
+            }
+            self.wildcard.push((suffix.to_string(), routes));
+            // Longest suffix first, so the first match is always the most specific
+            self.wildcard
+                .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+            Ok(())
+        } else if pattern.contains('*') {
+            Err(anyhow!(
+                "Wildcards are only allowed as the first label: {pattern}"
+            ))
+        } else if let std::collections::hash_map::Entry::Vacant(e) = self.exact.entry(pattern) {
+            e.insert(routes);
+            Ok(())
+        } else {
+            Err(anyhow!("Host already exists"))
+        }
+    }
+
+    pub fn set_default(&mut self, routes: Routes) {
+        self.default = routes;
+    }
+
+    pub const fn default_routes(&self) -> &Routes {
+        &self.default
+    }
+
+    /// Returns the routes responsible for the provided `Host` value
+    pub fn routes_for(&self, host: Option<&str>) -> &Routes {
+        let Some(host) = host.map(strip_port).map(normalize) else {
+            return &self.default;
+        };
+        self.exact
+            .get(&host)
+            .or_else(|| {
+                self.wildcard
+                    .iter()
+                    .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix))
+                    .map(|(_, routes)| routes)
+            })
+            .unwrap_or(&self.default)
+    }
+
+    pub fn apply(&self, request: &Request) -> Result<RouteResponse> {
+        self.routes_for(request.host()).apply(request)
+    }
+
+    pub fn check(&self, request: &Request) -> Result<Option<RouteResponse>> {
+        self.routes_for(request.host()).check(request)
+    }
+
+    pub fn streams_body(&self, request: &Request) -> bool {
+        self.routes_for(request.host()).streams_body(request)
+    }
+
+    pub async fn apply_async(&self, request: Request) -> Result<RouteResponse> {
+        self.routes_for(request.host()).apply_async(request).await
+    }
+}
+
+/// Host names are case insensitive, and a trailing '.' refers to the same host
+fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Removes the port from a `Host` value, keeping the brackets of an IPv6 literal
+pub fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal, the port if any comes after the closing bracket
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.rsplit_once(':').map_or(host, |(host, _)| host)
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    fmt::Write as _,
+    future::poll_fn,
+    io::Read,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use h2::{
+    server::{self, SendResponse},
+    RecvStream, SendStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinSet,
+    time::timeout,
+};
+use tracing::error;
+
+use crate::{
+    body::BodyStream,
+    config::Limits,
+    connection::{handler_failed, log_response, parse_failed, AsyncHandler},
+    request::{Method, Request, RequestError},
+    route::RouteResponse,
+    shutdown::Shutdown,
+};
+
+/// What every HTTP/2 connection starts with, which is how prior knowledge h2c is told apart from
+/// HTTP/1.1
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// How often the connection checks if it's been idle too long or the server is shutting down
+const SHUTDOWN_POLL: Duration = Duration::from_millis(250);
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// How much of a streamed body is read before it's sent as a frame
+const STREAM_BUFFER_SIZE: usize = 16 * 1024;
+/// Headers that only apply to HTTP/1.1 connections, which HTTP/2 doesn't allow
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Whether what the client has sent so far is the start of the HTTP/2 preface, the first 4 bytes
+/// are enough to rule out every HTTP/1.1 method
+pub fn is_preface(buf: &[u8]) -> bool {
+    let length = buf.len().min(PREFACE.len());
+    length >= 4 && buf[..length] == PREFACE[..length]
+}
+
+/// Serves an HTTP/2 connection, each request is handled on its own task so a slow one doesn't hold
+/// up the others
+///
+/// The connection is closed with a GOAWAY once it has been idle for the keep-alive timeout or the
+/// server starts shutting down, letting requests in flight finish first
+pub async fn serve<I: AsyncRead + AsyncWrite + Unpin>(
+    io: I,
+    peer_addr: Option<SocketAddr>,
+    secure: bool,
+    shutdown: &Arc<Shutdown>,
+    limits: Limits,
+    handler: &Arc<dyn AsyncHandler>,
+) {
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(u32::try_from(limits.max_header_size).unwrap_or(u32::MAX))
+        .handshake::<_, Bytes>(io);
+    let mut connection = match timeout(limits.request_timeout(), handshake).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(err)) => {
+            error!("HTTP/2 handshake failed with error: {err}");
+            return;
+        }
+        Err(_) => {
+            error!("HTTP/2 handshake timed out");
+            return;
+        }
+    };
+
+    let mut streams = JoinSet::new();
+    let mut idle = Instant::now();
+    let mut closing = false;
+    loop {
+        // Accepting is also what drives the connection, so it has to keep being polled while
+        // there are responses being sent
+        match timeout(SHUTDOWN_POLL, connection.accept()).await {
+            Ok(Some(Ok((request, respond)))) => {
+                let stream = Stream {
+                    peer_addr,
+                    secure,
+                    limits,
+                    handler: handler.clone(),
+                    shutdown: shutdown.clone(),
+                };
+                streams.spawn(stream.handle(request, respond));
+            }
+            Ok(Some(Err(err))) => {
+                error!("Failed to read from HTTP/2 connection with error: {err}");
+                break;
+            }
+            Ok(None) => break,
+            Err(_) => {}
+        }
+        while let Some(result) = streams.try_join_next() {
+            if result.is_err() {
+                error!("HTTP/2 stream panicked");
+            }
+        }
+        if !streams.is_empty() {
+            idle = Instant::now();
+        }
+        if !closing && (shutdown.is_triggered() || idle.elapsed() >= limits.keep_alive_timeout()) {
+            connection.graceful_shutdown();
+            closing = true;
+        }
+    }
+}
+
+/// Everything a single request on the connection needs to be answered
+struct Stream {
+    peer_addr: Option<SocketAddr>,
+    secure: bool,
+    limits: Limits,
+    handler: Arc<dyn AsyncHandler>,
+    shutdown: Arc<Shutdown>,
+}
+
+impl Stream {
+    async fn handle(self, request: http::Request<RecvStream>, mut respond: SendResponse<Bytes>) {
+        let request = timeout(self.limits.request_timeout(), self.read_request(request))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timed out reading request")));
+        let (request, route_response) = match request {
+            Ok(request) => {
+                let route_response = self
+                    .handler
+                    .apply(request.clone())
+                    .await
+                    .unwrap_or_else(|err| handler_failed(&request, &err));
+                (Some(request), route_response)
+            }
+            Err(err) => (None, parse_failed(&err)),
+        };
+        let source_addr = self
+            .peer_addr
+            .map_or_else(|| String::from("Unix Socket"), |addr| addr.to_string());
+        if route_response.should_log() {
+            log_response(&route_response, request.as_ref(), &source_addr);
+        }
+        if let Err(err) = send_response(&mut respond, route_response, request.as_ref()).await {
+            error!("Failed to write HTTP/2 response with error: {err}");
+            return;
+        }
+        self.shutdown.request_served();
+    }
+
+    /// Turns the request into the same form HTTP/1.1 requests are parsed into, so routes can't
+    /// tell them apart
+    async fn read_request(&self, request: http::Request<RecvStream>) -> Result<Request> {
+        let (parts, mut body) = request.into_parts();
+        let target = if parts.method == http::Method::CONNECT {
+            parts.uri.authority().map(ToString::to_string)
+        } else {
+            parts.uri.path_and_query().map(ToString::to_string)
+        };
+        let mut head = format!(
+            "{} {} HTTP/2.0\r\n",
+            parts.method,
+            target.context("Request is missing its :path")?
+        );
+        // Routing by host looks at the Host header, which :authority replaces
+        if !parts.headers.contains_key(http::header::HOST) {
+            if let Some(authority) = parts.uri.authority() {
+                let _ = write!(head, "Host: {authority}\r\n");
+            }
+        }
+        // Cookies may be split across several fields for better compression
+        let cookies: Vec<&str> = parts
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .map(|value| value.to_str())
+            .collect::<Result<_, _>>()
+            .context("Invalid Cookie header")?;
+        if !cookies.is_empty() {
+            let _ = write!(head, "Cookie: {}\r\n", cookies.join("; "));
+        }
+        for (name, value) in &parts.headers {
+            if name == http::header::COOKIE {
+                continue;
+            }
+            let value = value
+                .to_str()
+                .with_context(|| format!("Invalid {name} header"))?;
+            let _ = write!(head, "{name}: {value}\r\n");
+        }
+        head.push_str("\r\n");
+        let mut request = Request::parse_head(head.as_bytes(), self.limits.max_header_size)?;
+        request.set_connection(self.peer_addr, self.secure);
+
+        let limit = self.limits.max_body_size;
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.context("Failed to read request body")?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            if data.len() + chunk.len() > limit {
+                return Err(RequestError::BodyTooLarge {
+                    length: data.len() + chunk.len(),
+                    limit,
+                }
+                .into());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        if !data.is_empty() {
+            request.set_body(data);
+        }
+        Ok(request)
+    }
+}
+
+async fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    mut route_response: RouteResponse,
+    request: Option<&Request>,
+) -> Result<()> {
+    let stream = route_response.take_stream();
+    let headers = route_response.headers_mut();
+    for name in CONNECTION_SPECIFIC {
+        headers.remove(name);
+    }
+    headers.remove("Content-Length");
+    let allows_body = route_response.code().allows_body()
+        && request.is_none_or(|request| request.method() != Method::HEAD);
+    let length = stream.as_ref().map_or_else(
+        || Some(route_response.content().len() as u64),
+        BodyStream::length,
+    );
+
+    let mut response = http::Response::builder().status(route_response.code() as u16);
+    for (name, value) in route_response.headers().iter() {
+        response = response.header(name, value);
+    }
+    if let Some(length) = length.filter(|_| route_response.code().allows_body()) {
+        response = response.header(http::header::CONTENT_LENGTH, length);
+    }
+    let response = response.body(()).context("Invalid response headers")?;
+    let mut send = respond.send_response(response, !allows_body)?;
+    if !allows_body {
+        return Ok(());
+    }
+
+    match stream {
+        Some(stream) => {
+            let mut reader = stream.into_reader();
+            let mut buf = vec![0; STREAM_BUFFER_SIZE];
+            loop {
+                // Streamed bodies are read with blocking I/O
+                let read = tokio::task::block_in_place(|| reader.read(&mut buf))?;
+                if read == 0 {
+                    return send_data(&mut send, Bytes::new(), true).await;
+                }
+                send_data(&mut send, Bytes::copy_from_slice(&buf[..read]), false).await?;
+            }
+        }
+        None => {
+            send_data(
+                &mut send,
+                Bytes::from(route_response.content().to_string()),
+                true,
+            )
+            .await
+        }
+    }
+}
+
+/// Sends the data as the client's flow control window allows
+async fn send_data(
+    send: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end_of_stream: bool,
+) -> Result<()> {
+    if data.is_empty() {
+        if end_of_stream {
+            send.send_data(data, true)?;
+        }
+        return Ok(());
+    }
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = poll_fn(|cx| send.poll_capacity(cx))
+            .await
+            .context("Stream closed before its response was sent")??;
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
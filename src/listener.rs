@@ -0,0 +1,301 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{
+    fs,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use socket2::{Domain, Socket, Type};
+
+use crate::stream::{AsyncStream, Stream};
+
+/// Connections waiting to be accepted before the OS starts refusing them
+const BACKLOG: i32 = 1024;
+
+/// Where a listener should bind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A listener as provided on the command line, `ADDRESS[,tls]`
+///
+/// `ADDRESS` is either a TCP socket address, ie: `127.0.0.1:8080` or `[::]:8080`, or a Unix socket
+/// path prefixed with `unix:`, ie: `unix:/run/webserver.sock`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ListenSpec {
+    pub address: Address,
+    pub tls: bool,
+}
+
+impl TryFrom<String> for ListenSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl FromStr for ListenSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, tls) = s
+            .strip_suffix(",tls")
+            .map_or((s, false), |address| (address, true));
+        let address = if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(anyhow!("Unix socket path must not be empty"));
+            }
+            Address::Unix(path.into())
+        } else if address.contains(',') {
+            return Err(anyhow!("Unknown listener option in: {s}"));
+        } else {
+            Address::Tcp(address.to_string())
+        };
+        Ok(Self { address, tls })
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+        // Sockets we didn't create, or that another process is still using, must be left in place
+        remove_on_drop: bool,
+    },
+}
+
+impl Listener {
+    pub fn bind(address: &Address) -> Result<Self> {
+        match address {
+            Address::Tcp(address) => {
+                let address = address
+                    .to_socket_addrs()
+                    .with_context(|| format!("Invalid listen address: {address}"))?
+                    .next()
+                    .with_context(|| format!("Failed to resolve listen address: {address}"))?;
+                Ok(Self::Tcp(bind_tcp(address)?))
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind unix socket: {}", path.display()))?;
+                Ok(Self::Unix {
+                    listener,
+                    path: path.clone(),
+                    remove_on_drop: true,
+                })
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(anyhow!("Unix sockets are not supported on this platform")),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                listener.accept().map(|(stream, _)| Stream::Unix(stream))
+            }
+        }
+    }
+
+    /// The address that was actually bound, which includes the assigned port when binding port 0
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Self::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+            #[cfg(unix)]
+            Self::Unix { path, .. } => Ok(Address::Unix(path.clone())),
+        }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix { .. } => None,
+        }
+    }
+
+    /// Registers a copy of the socket with the async runtime
+    ///
+    /// This listener still owns the socket file, so it needs to be kept around until the async one
+    /// is done with it
+    pub fn to_async(&self) -> io::Result<AsyncListener> {
+        match self {
+            Self::Tcp(listener) => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                tokio::net::TcpListener::from_std(listener).map(AsyncListener::Tcp)
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                tokio::net::UnixListener::from_std(listener).map(AsyncListener::Unix)
+            }
+        }
+    }
+
+    /// Wraps a listening socket inherited from another process
+    #[cfg(unix)]
+    pub fn inherit_unix(listener: UnixListener) -> Result<Self> {
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .context("Inherited unix socket is not bound to a path")?
+            .to_path_buf();
+        Ok(Self::Unix {
+            listener,
+            path,
+            remove_on_drop: false,
+        })
+    }
+
+    /// Leaves the socket file in place when this listener is dropped
+    pub const fn keep_socket_file(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix { remove_on_drop, .. } = self {
+            *remove_on_drop = false;
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn try_clone_fd(&self) -> io::Result<OwnedFd> {
+        match self {
+            Self::Tcp(listener) => listener.as_fd().try_clone_to_owned(),
+            Self::Unix { listener, .. } => listener.as_fd().try_clone_to_owned(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix {
+            path,
+            remove_on_drop: true,
+            ..
+        } = self
+        {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl AsyncListener {
+    pub async fn accept(&self) -> io::Result<AsyncStream> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                Ok(AsyncStream::new(stream, Some(peer_addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(AsyncStream::new(stream, None))
+            }
+        }
+    }
+}
+
+/// Connects to a bound address so that a thread blocked accepting on it wakes up
+pub fn wake(address: &Address) {
+    match address {
+        Address::Tcp(address) => {
+            let Ok(mut address) = address.parse::<SocketAddr>() else {
+                return;
+            };
+            if address.ip().is_unspecified() {
+                address.set_ip(if address.is_ipv4() {
+                    Ipv4Addr::LOCALHOST.into()
+                } else {
+                    Ipv6Addr::LOCALHOST.into()
+                });
+            }
+            let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            let _ = UnixStream::connect(path);
+        }
+        #[cfg(not(unix))]
+        Address::Unix(_) => {}
+    }
+}
+
+/// Removes a socket file left behind by a previous run, which would make the bind fail
+///
+/// Anything that isn't a socket, or a socket that another server is still accepting on, is left
+/// in place and reported as in use
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    let in_use = || {
+        Err(io::Error::from(io::ErrorKind::AddrInUse))
+            .with_context(|| format!("Failed to bind unix socket: {}", path.display()))
+    };
+    if !metadata.file_type().is_socket() {
+        return in_use();
+    }
+    match UnixStream::connect(path) {
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket: {}", path.display())),
+        _ => in_use(),
+    }
+}
+
+/// Binds a TCP listener, IPv6 listeners also accept IPv4 connections where the OS allows it
+fn bind_tcp(address: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket
+        .bind(&address.into())
+        .with_context(|| format!("Failed to bind address: {address}"))?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
@@ -1,128 +1,685 @@
 use std::{
-    io::{BufReader, Write},
-    net::{TcpListener, TcpStream},
-    ops::Deref,
-    sync::Arc,
+    fmt::Write as _,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
     thread::{self},
     time::Duration,
 };
 
-use anyhow::Result;
+use ahash::HashMap;
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use codes::ResponseCode;
-use request::{Method, Request};
-use route::{Route, Routes};
+use config::{Config, Logging, RuntimeMode, TlsConfig};
+use connection::{handle_connection, parse_failed, reject_connection, Handler};
+use cookie::{Cookie, CookieJar, Key, SameSite};
+use extract::{Json, Path, Query, State, TypedFn};
+use form::{MultipartLimits, Part};
+use listener::{Address, ListenSpec, Listener};
+use reload::Reloader;
+use request::Request;
+use route::{HandlerFn, RouteResponse};
+use serde::Deserialize;
+use session::Session;
+use shutdown::Shutdown;
+use sse::Event;
 use threadpool::ThreadPool;
-use tracing::{error, info, warn};
+use tls::Tls;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use websocket::Message;
 
+mod async_runtime;
+mod balance;
+mod body;
+mod cgi;
 mod codes;
+mod config;
+mod connection;
+mod cookie;
+mod extract;
+mod fastcgi;
+mod form;
+#[cfg(unix)]
+mod handoff;
+mod headers;
+mod hosts;
+mod http2;
+mod listener;
+mod proxy;
+mod reload;
 mod request;
 mod response;
 mod route;
+mod session;
+mod shutdown;
+mod sse;
+mod stream;
+mod tls;
+mod websocket;
 
 pub static SUPPORTED_HTTP_VERSION: &str = "HTTP/1.1";
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, author, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value_t = String::from("127.0.0.1"))]
-    address: String,
-    #[arg(short, long, default_value_t = String::from("0"))]
-    port: String,
-    #[arg(short, long, default_value_t = 0)]
-    threads: u8,
+    /// TOML config file, defaults to webserver.toml if it exists. Any arguments provided here
+    /// take priority over the values in the file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[arg(short, long)]
+    address: Option<String>,
+    #[arg(short, long)]
+    port: Option<String>,
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// Serve connections from a thread pool, or an async runtime
+    #[arg(long, value_enum)]
+    runtime: Option<RuntimeMode>,
     #[arg(short = 'i', long, default_value_t = false)]
     auto_index: bool,
+    /// PEM certificate chain, enables TLS when provided along with --key
+    #[arg(long)]
+    cert: Option<PathBuf>,
+    /// PEM private key for --cert
+    #[arg(long)]
+    key: Option<PathBuf>,
+    /// Additional certificate selected by SNI, in the form HOST=CERT,KEY
+    #[arg(long)]
+    sni: Vec<String>,
+    /// Address to listen on, in the form ADDRESS[,tls] or unix:PATH[,tls], may be repeated.
+    /// Replaces --address and --port when provided
+    #[arg(short, long)]
+    listen: Vec<ListenSpec>,
+    /// Port for a plaintext listener that redirects every request to HTTPS
+    #[arg(long)]
+    redirect_port: Option<u16>,
+    /// Seconds to wait for in-flight connections to finish when shutting down
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long)]
+    log_level: Option<String>,
 }
 
+/// The config file used when --config isn't provided
+const DEFAULT_CONFIG: &str = "webserver.toml";
+const DEFAULT_ADDRESS: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "0";
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
     human_panic::setup_panic!();
 
-    tracing_subscriber::registry().with(fmt::layer()).init();
+    // Found once, so a reload fails rather than falling back to the defaults if the file goes away
+    let config_path = config_path(&args);
+    let config = load_config(config_path.as_deref(), &args)?;
+    init_logging(&config.logging)?;
 
     info!("Starting Webserver");
-    let socket = TcpListener::bind(format!("{}:{}", args.address, args.port))?;
-    let address = socket.local_addr()?;
-    info!("Socket bound to address: {}", &address);
-
-    let mut routes = Routes::default();
-    routes.set_auto_index(args.auto_index);
-
-    routes.add_static("/", "static/hello.html", None)?;
-    routes.set_404(Route::Static(
-        "static/404.html".into(),
-        Some(ResponseCode::Not_Found),
-    ));
-    routes.add_dynamic("/sleep", vec![Method::GET, Method::POST], |request| {
-        let duration = request.body().map_or("5", |v| v).parse().unwrap_or(5);
-        info!("Sleeping for {duration} seconds");
-        thread::sleep(Duration::from_secs(duration));
-        Ok(("Sleeping", ResponseCode::Ok).into())
-    })?;
-    routes.set_static_dir("static/");
-    routes.add_plain("/plain", "Test Plain", None)?;
-
-    if args.threads == 1 {
-        for stream in socket.incoming() {
-            handle_connection(stream?, &routes);
+    let shutdown = Arc::new(Shutdown::default());
+    shutdown.listen_for_signals()?;
+    let tls = build_tls(config.tls.as_ref())?;
+
+    // Check the routes before binding anything so a bad config doesn't take over the sockets
+    let reloader = Arc::new(Reloader::new(
+        config_path.clone(),
+        config.clone(),
+        handlers(),
+        move || load_config(config_path.as_deref(), &args),
+    )?);
+    reloader.spawn_watcher()?;
+
+    let (listeners, redirect) = bind_listeners(&config, tls.as_ref(), &shutdown)?;
+    #[cfg(unix)]
+    handoff::Handoff::new(
+        listeners
+            .iter()
+            .map(|(listener, _)| listener)
+            .chain(redirect.as_ref().map(|(listener, _)| listener)),
+    )?
+    .listen_for_restart(shutdown.clone())?;
+
+    let deadline = Duration::from_secs(config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT));
+    match config.runtime {
+        RuntimeMode::Threads => run_threads(
+            listeners,
+            redirect,
+            config.threads,
+            &reloader,
+            &shutdown,
+            deadline,
+        ),
+        RuntimeMode::Async => async_runtime::run(
+            listeners,
+            redirect,
+            config.threads,
+            &reloader,
+            &shutdown,
+            deadline,
+        )?,
+    }
+    shutdown.log_summary();
+
+    Ok(())
+}
+
+/// Serves every listener from its own thread, handing connections to a pool if there is one
+fn run_threads(
+    listeners: Vec<(Listener, Option<Arc<Tls>>)>,
+    redirect: Option<(Listener, u16)>,
+    threads: Option<usize>,
+    reloader: &Arc<Reloader>,
+    shutdown: &Arc<Shutdown>,
+    deadline: Duration,
+) {
+    let handler: Arc<dyn Handler> = reloader.clone();
+
+    let pool = match threads.unwrap_or(0) {
+        // A single thread means every connection is handled on its listener's thread
+        1 => None,
+        0 => Some(ThreadPool::default()),
+        threads => Some(ThreadPool::new(threads)),
+    };
+    let mut listener_threads: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, tls)| {
+            let handler = handler.clone();
+            let pool = pool.clone();
+            let shutdown = shutdown.clone();
+            let reloader = reloader.clone();
+            thread::spawn(move || {
+                serve(
+                    listener,
+                    tls.as_deref(),
+                    &handler,
+                    pool.as_ref(),
+                    &shutdown,
+                    &reloader,
+                );
+            })
+        })
+        .collect();
+    if let Some((listener, https_port)) = redirect {
+        let handler: Arc<dyn Handler> =
+            Arc::new(move |request: &Request| Ok(tls::redirect_to_https(request, https_port)));
+        let pool = pool.clone();
+        let shutdown = shutdown.clone();
+        let reloader = reloader.clone();
+        listener_threads.push(thread::spawn(move || {
+            serve(
+                listener,
+                None,
+                &handler,
+                pool.as_ref(),
+                &shutdown,
+                &reloader,
+            );
+        }));
+    }
+    for handle in listener_threads {
+        if handle.join().is_err() {
+            error!("Listener thread panicked");
         }
+    }
+
+    if let Some(pool) = pool {
+        drain(pool, shutdown, deadline);
+    }
+}
+
+fn init_logging(logging: &Logging) -> Result<()> {
+    tracing_subscriber::registry()
+        .with(logging.level()?)
+        .with(
+            logging
+                .compact
+                .then(|| fmt::layer().compact().with_ansi(logging.ansi)),
+        )
+        .with((!logging.compact).then(|| fmt::layer().with_ansi(logging.ansi)))
+        .init();
+    Ok(())
+}
+
+fn build_tls(tls: Option<&TlsConfig>) -> Result<Option<Arc<Tls>>> {
+    Ok(match tls {
+        Some(TlsConfig {
+            cert: Some(cert),
+            key: Some(key),
+            sni,
+            ..
+        }) => {
+            let tls = Tls::new(cert, key, sni)?;
+            tls.spawn_reloader();
+            Some(Arc::new(tls))
+        }
+        Some(TlsConfig {
+            cert: None,
+            key: None,
+            sni,
+            redirect_port: None,
+        }) if sni.is_empty() => None,
+        Some(_) => return Err(anyhow!("tls: cert and key are required to enable TLS")),
+        None => None,
+    })
+}
+
+/// Binds every listener, along with the HTTPS redirect listener and the port it redirects to
+///
+/// Sockets inherited from systemd or a restarting process are used in place of binding new ones
+#[allow(clippy::type_complexity)]
+fn bind_listeners(
+    config: &Config,
+    tls: Option<&Arc<Tls>>,
+    shutdown: &Shutdown,
+) -> Result<(Vec<(Listener, Option<Arc<Tls>>)>, Option<(Listener, u16)>)> {
+    let specs = if config.listen.is_empty() {
+        vec![ListenSpec {
+            address: Address::Tcp(format!("{DEFAULT_ADDRESS}:{DEFAULT_PORT}")),
+            tls: tls.is_some(),
+        }]
     } else {
-        let routes = Arc::from(routes);
-        let pool = if args.threads == 0 {
-            ThreadPool::default()
+        config.listen.clone()
+    };
+    let redirect_port = config.tls.as_ref().and_then(|tls| tls.redirect_port);
+
+    #[cfg(unix)]
+    let mut inherited = handoff::Inherited::from_env();
+    #[cfg(unix)]
+    if let Some(inherited) = &inherited {
+        let expected = specs.len() + usize::from(redirect_port.is_some());
+        if inherited.len() != expected {
+            return Err(anyhow!(
+                "Inherited {} sockets, but {expected} listeners are configured",
+                inherited.len()
+            ));
+        }
+    }
+    let mut bind = |index: usize, address: &Address| -> Result<Listener> {
+        #[cfg(unix)]
+        if let Some(inherited) = &mut inherited {
+            return inherited.take(index, address);
+        }
+        let _ = index;
+        Listener::bind(address)
+    };
+
+    let mut listeners = Vec::new();
+    for (index, spec) in specs.iter().enumerate() {
+        let tls = if spec.tls {
+            Some(tls.cloned().with_context(|| {
+                format!(
+                    "Listener {} requires TLS, but no certificate was provided",
+                    spec.address
+                )
+            })?)
         } else {
-            ThreadPool::new(args.threads as usize)
+            None
         };
-        for stream in socket.incoming() {
-            let routes = routes.clone();
-            pool.execute(move || handle_connection(stream.unwrap(), routes));
-        }
+        let listener = bind(index, &spec.address)?;
+        info!(
+            "Socket bound to address: {}{}",
+            listener.local_addr()?,
+            if tls.is_some() { " with TLS" } else { "" }
+        );
+        shutdown.add_listener(listener.local_addr()?);
+        listeners.push((listener, tls));
     }
 
-    Ok(())
+    let redirect = if let Some(redirect_port) = redirect_port {
+        // The redirect listens on the same interface as the HTTPS listener it redirects to
+        let https = listeners
+            .iter()
+            .find(|(_, tls)| tls.is_some())
+            .and_then(|(listener, _)| listener.socket_addr())
+            .context("tls.redirect_port requires a TCP listener with TLS")?;
+        let address = Address::Tcp(SocketAddr::new(https.ip(), redirect_port).to_string());
+        let https_port = https.port();
+        let listener = bind(specs.len(), &address)?;
+        info!(
+            "HTTPS redirect bound to address: {}",
+            listener.local_addr()?
+        );
+        shutdown.add_listener(listener.local_addr()?);
+        Some((listener, https_port))
+    } else {
+        None
+    };
+    Ok((listeners, redirect))
 }
 
-fn handle_connection<R: Deref<Target = Routes>>(mut stream: TcpStream, routes: R) {
-    let buf_reader = BufReader::new(&mut stream);
-    let (request, route_response) = Request::parse(buf_reader).map_or_else(
-        |err| {
-            error!("Failed to parse Request with error: {err}");
-            (None, ("Failed to parse", ResponseCode::Bad_Request).into())
-        },
-        |request| {
-            //tracing::debug!("Received Request:\n{}", &request.as_string());
-            (Some(request.clone()), routes.apply(&request).unwrap())
-        },
+/// Handlers that routes in the config file can refer to by name
+fn handlers() -> HashMap<&'static str, HandlerFn> {
+    let mut handlers: HashMap<&'static str, HandlerFn> = HashMap::default();
+    handlers.insert(
+        "sleep",
+        HandlerFn::Sync(|request| {
+            let duration = request.body().map_or("5", |v| v).parse().unwrap_or(5);
+            info!("Sleeping for {duration} seconds");
+            thread::sleep(Duration::from_secs(duration));
+            Ok(("Sleeping", ResponseCode::Ok).into())
+        }),
     );
+    handlers.insert(
+        "sleep_async",
+        HandlerFn::Async(|request| {
+            Box::pin(async move {
+                let duration = request.body().map_or("5", |v| v).parse().unwrap_or(5);
+                info!("Sleeping for {duration} seconds without blocking");
+                tokio::time::sleep(Duration::from_secs(duration)).await;
+                Ok(("Sleeping", ResponseCode::Ok).into())
+            })
+        }),
+    );
+    handlers.insert(
+        "ticks",
+        HandlerFn::Sync(|request| {
+            let (events, response) = sse::event_stream(request);
+            // Reconnecting clients carry on from the tick after the last one they saw
+            let start = events
+                .last_event_id()
+                .and_then(|id| id.parse::<u64>().ok())
+                .map_or(0, |id| id + 1);
+            thread::spawn(move || {
+                for tick in start.. {
+                    let event = Event::new(format!("Tick {tick}"))
+                        .with_id(tick.to_string())
+                        .with_event("tick");
+                    if events.send(event).is_err() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+            });
+            Ok(response)
+        }),
+    );
+    handlers.insert(
+        "echo",
+        HandlerFn::WebSocket(|_, socket| loop {
+            match socket.recv()? {
+                Message::Text(text) => socket.send_text(&text)?,
+                Message::Binary(data) => socket.send_binary(&data)?,
+                Message::Close { .. } => return Ok(()),
+            }
+        }),
+    );
+    handlers.insert(
+        "greet",
+        HandlerFn::Sync(|request| match request.json::<Greeting>() {
+            Ok(greeting) => RouteResponse::json(&serde_json::json!({
+                "message": format!("Hello, {}!", greeting.name),
+            })),
+            Err(err) => Ok(RouteResponse::json_rejection(&err)),
+        }),
+    );
+    handlers.insert(
+        "add_item",
+        HandlerFn::Typed(TypedFn::with_state(add_item, Arc::new(AtomicU64::new(0)))),
+    );
+    // The key is generated on start, so the cookies it signs don't outlive the server
+    let key = Key::generate().expect("Failed to generate cookie key");
+    handlers.insert("visits", HandlerFn::Typed(TypedFn::with_state(visits, key)));
+    handlers.insert("login", HandlerFn::Typed(TypedFn::new(login)));
+    handlers.insert("whoami", HandlerFn::Typed(TypedFn::new(whoami)));
+    handlers.insert("logout", HandlerFn::Typed(TypedFn::new(logout)));
+    handlers.insert(
+        "upload",
+        HandlerFn::Sync(|request| {
+            Ok(describe_upload(request).map_or_else(
+                |err| parse_failed(&err),
+                |summary| (summary, ResponseCode::Ok).into(),
+            ))
+        }),
+    );
+    handlers
+}
 
-    if route_response.should_log() {
-        let source_addr = stream.peer_addr().unwrap();
-        if let Some(context) = route_response.context() {
-            warn!(
-                r#"Route Requested logging with context: {context}
-                Source Address: {source_addr}
-                Request Body: {}"#,
-                request.map_or("None".into(), |request| request.as_string())
-            );
+/// The body the "greet" handler expects
+#[derive(Deserialize)]
+struct Greeting {
+    name: String,
+}
+
+/// The parameters in the `add_item` handler's target
+#[derive(Deserialize)]
+struct ListParams {
+    list: String,
+}
+
+#[derive(Deserialize)]
+struct ItemOptions {
+    quantity: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    name: String,
+}
+
+/// Adds an item to a list, counting every item added since the server started
+#[allow(clippy::needless_pass_by_value)]
+fn add_item(
+    Path(params): Path<ListParams>,
+    Query(options): Query<ItemOptions>,
+    Json(item): Json<Item>,
+    State(added): State<Arc<AtomicU64>>,
+) -> Result<RouteResponse> {
+    let quantity = u64::from(options.quantity.unwrap_or(1));
+    let total = added.fetch_add(quantity, Ordering::Relaxed) + quantity;
+    RouteResponse::json(&serde_json::json!({
+        "list": params.list,
+        "item": item.name,
+        "quantity": quantity,
+        "total_added": total,
+    }))
+}
+
+/// Counts the client's visits in a signed cookie, which starts over if it's been tampered with
+#[allow(clippy::needless_pass_by_value)]
+fn visits(cookies: CookieJar, State(key): State<Key>) -> Result<RouteResponse> {
+    let visits = cookies
+        .get_signed("visits", &key)
+        .and_then(|visits| visits.parse::<u64>().ok())
+        .unwrap_or(0)
+        + 1;
+    let cookie = Cookie::new("visits", visits.to_string())
+        .with_path("/")
+        .with_max_age(Duration::from_hours(30 * 24))
+        .with_http_only()
+        .with_same_site(SameSite::Lax)
+        .signed(&key);
+    RouteResponse::from((format!("Visits: {visits}\n"), ResponseCode::Ok)).with_cookie(&cookie)
+}
+
+#[derive(Deserialize)]
+struct Login {
+    name: String,
+}
+
+/// Stores the name in the session, moving it to a new id now that it's logged in
+#[allow(clippy::needless_pass_by_value)]
+fn login(session: Session, Json(login): Json<Login>) -> Result<RouteResponse> {
+    session.rotate();
+    session.insert("user", &login.name)?;
+    RouteResponse::json(&serde_json::json!({ "user": login.name }))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn whoami(session: Session) -> Result<RouteResponse> {
+    session.get::<String>("user")?.map_or_else(
+        || {
+            Ok(RouteResponse::json_error(
+                ResponseCode::Unauthorized,
+                "Not logged in",
+            ))
+        },
+        |user| RouteResponse::json(&serde_json::json!({ "user": user })),
+    )
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn logout(session: Session) -> Result<RouteResponse> {
+    session.destroy();
+    RouteResponse::json(&serde_json::json!({ "user": null }))
+}
+
+/// Lists the fields and files of a form post, which can be either encoding
+fn describe_upload(request: &Request) -> Result<String> {
+    let mut summary = String::new();
+    let urlencoded = request
+        .headers()
+        .content_type()
+        .is_some_and(|content_type| content_type.essence() == "application/x-www-form-urlencoded");
+    if urlencoded {
+        for (name, values) in request.form()?.iter() {
+            writeln!(summary, "{name} = {}", values.join(", "))?;
+        }
+        return Ok(summary);
+    }
+    let mut parts = request.multipart(MultipartLimits::default())?;
+    while let Some(part) = parts.next_part()? {
+        match part {
+            Part::Field { name, value } => writeln!(summary, "{name} = {value}")?,
+            Part::File(file) => writeln!(
+                summary,
+                "{} = {} ({} bytes)",
+                file.name(),
+                file.filename(),
+                file.size()
+            )?,
+        }
+    }
+    Ok(summary)
+}
+
+fn config_path(args: &Args) -> Option<PathBuf> {
+    args.config.clone().or_else(|| {
+        let path = PathBuf::from(DEFAULT_CONFIG);
+        path.exists().then_some(path)
+    })
+}
+
+/// Loads the config file and applies the command line arguments on top of it
+fn load_config(path: Option<&std::path::Path>, args: &Args) -> Result<Config> {
+    let mut config = path.map_or_else(|| Ok(Config::default()), Config::load)?;
+    let args = args.clone();
+
+    if !args.listen.is_empty() {
+        config.listen = args.listen;
+    } else if args.address.is_some() || args.port.is_some() {
+        config.listen = vec![ListenSpec {
+            address: Address::Tcp(format!(
+                "{}:{}",
+                args.address.as_deref().unwrap_or(DEFAULT_ADDRESS),
+                args.port.as_deref().unwrap_or(DEFAULT_PORT)
+            )),
+            tls: args.cert.is_some(),
+        }];
+    }
+    if let Some(runtime) = args.runtime {
+        config.runtime = runtime;
+    }
+    config.threads = args.threads.or(config.threads);
+    config.shutdown_timeout = args.shutdown_timeout.or(config.shutdown_timeout);
+    if args.auto_index {
+        config.site.auto_index = true;
+    }
+    if let Some(level) = args.log_level {
+        config.logging.level = level;
+    }
+
+    if args.cert.is_some()
+        || args.key.is_some()
+        || !args.sni.is_empty()
+        || args.redirect_port.is_some()
+    {
+        let tls = config.tls.get_or_insert_with(TlsConfig::default);
+        tls.cert = args.cert.or_else(|| tls.cert.take());
+        tls.key = args.key.or_else(|| tls.key.take());
+        if !args.sni.is_empty() {
+            tls.sni = args.sni;
+        }
+        tls.redirect_port = args.redirect_port.or(tls.redirect_port);
+    }
+    Ok(config)
+}
+
+/// Accepts connections until shutdown, handing each one to the pool if there is one
+fn serve(
+    mut listener: Listener,
+    tls: Option<&Tls>,
+    handler: &Arc<dyn Handler>,
+    pool: Option<&ThreadPool>,
+    shutdown: &Arc<Shutdown>,
+    reloader: &Reloader,
+) {
+    let _guard = shutdown.track_listener();
+    loop {
+        let stream = listener
+            .accept()
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| match tls {
+                Some(tls) => tls.accept(stream),
+                None => Ok(stream),
+            });
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) if shutdown.is_triggered() => {
+                error!("Failed to accept connection with error: {err}");
+                break;
+            }
+            Err(err) => {
+                error!("Failed to accept connection with error: {err}");
+                continue;
+            }
+        };
+        // After shutdown this is usually the connection that woke us up, but it could also be a
+        // real client that raced it, so it still gets served. The wake up closes without sending
+        // anything, so it costs next to nothing
+        let limits = reloader.live().limits;
+        if let Some(pool) = pool {
+            let queued = pool.queued_count();
+            if limits.max_pending_connections > 0 && queued >= limits.max_pending_connections {
+                warn!("{queued} connections waiting for a thread, rejecting new connection");
+                reject_connection(stream, shutdown);
+                continue;
+            }
+            debug!("{queued} connections waiting for a thread");
+            let handler = handler.clone();
+            let shutdown = shutdown.clone();
+            pool.execute(move || handle_connection(stream, &shutdown, limits, &handler));
         } else {
-            warn!(
-                r#"Route Requested logging
-                Source Address: {source_addr}
-                Request Body: {}"#,
-                request.map_or("None".into(), |request| request.as_string())
-            );
+            handle_connection(stream, shutdown, limits, handler);
         }
+        if shutdown.is_triggered() {
+            break;
+        }
+    }
+    if shutdown.is_handed_off() {
+        // The new process is still listening on it
+        listener.keep_socket_file();
     }
+}
 
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        response::StatusLine::new(route_response.code()),
-        route_response.content().len(),
-        route_response.content()
+/// Waits for every queued and in-flight connection to finish, up to the deadline
+fn drain(pool: ThreadPool, shutdown: &Shutdown, deadline: Duration) {
+    info!(
+        "Waiting up to {deadline:?} for {} connections to finish",
+        shutdown.active_connections() + pool.queued_count()
     );
-
-    stream.write_all(response.as_bytes()).unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        pool.join();
+        let _ = sender.send(());
+    });
+    if receiver.recv_timeout(deadline).is_err() {
+        warn!("Shutdown deadline exceeded, dropping remaining connections");
+    }
 }
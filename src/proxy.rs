@@ -0,0 +1,311 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    balance::{BalanceOptions, Lease, Pool, Upstream},
+    body::{BodyStream, ChunkedReader},
+    codes::ResponseCode,
+    headers::Headers,
+    request::{encode_path, Method, Request},
+    route::{gateway_failed, normalize_prefix, strip_prefix, RouteResponse},
+};
+
+/// How long the upstream has to accept the connection, and then to send each part of its response
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that only apply to a single connection, so they're never passed through
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Forwards requests under a path prefix to one of a set of upstream HTTP/1.1 servers
+///
+/// When an upstream URL has a path, it replaces the prefix, so a prefix of `/api` proxied to
+/// `http://localhost:3000/v1` sends `/api/users` upstream as `/v1/users`. Otherwise the path is
+/// passed on unchanged.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    prefix: String,
+    pool: Arc<Pool>,
+    timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new(
+        prefix: &str,
+        upstreams: &[String],
+        timeout: Option<Duration>,
+        options: BalanceOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            prefix: normalize_prefix(prefix),
+            pool: Pool::new(upstreams, options)?,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Sends the request upstream and streams its response back
+    ///
+    /// Upstreams that can't be reached or send something invalid get a 502, ones that are too slow
+    /// get a 504
+    pub fn forward(&self, request: &Request) -> RouteResponse {
+        self.try_forward(request)
+            .unwrap_or_else(|err| gateway_failed(request, &err.context("Proxying failed")))
+    }
+
+    fn try_forward(&self, request: &Request) -> Result<RouteResponse> {
+        let (lease, stream) = self.connect(request)?;
+        let response = self.exchange(request, lease.upstream(), stream);
+        match response {
+            Ok((code, headers, reader)) => {
+                lease.succeeded();
+                Self::respond(request, code, headers, reader, lease)
+            }
+            Err(err) => {
+                lease.failed();
+                Err(err.context(format!("Upstream {}", lease.upstream().authority())))
+            }
+        }
+    }
+
+    /// Connects to the first upstream that will accept the connection
+    ///
+    /// Nothing has been sent yet, so it's always safe to move on to the next upstream
+    fn connect(&self, request: &Request) -> Result<(Lease, TcpStream)> {
+        let client = request.peer_addr().map(|addr| addr.ip());
+        let mut last_err = None;
+        for index in self.pool.candidates(client) {
+            let lease = self.pool.lease(index);
+            match lease.upstream().connect(self.timeout) {
+                Ok(stream) => return Ok((lease, stream)),
+                Err(err) => {
+                    lease.failed();
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("No upstreams to connect to")))
+    }
+
+    /// Sends the request and reads the head of the response
+    fn exchange(
+        &self,
+        request: &Request,
+        upstream: &Upstream,
+        mut stream: TcpStream,
+    ) -> Result<(ResponseCode, Headers, BufReader<TcpStream>)> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream
+            .write_all(&self.upstream_request(request, upstream))
+            .and_then(|()| stream.flush())
+            .context("Failed to send request upstream")?;
+
+        let mut reader = BufReader::new(stream);
+        let (code, headers) = loop {
+            let (code, headers) = read_head(&mut reader)?;
+            // Interim responses, like 100 Continue, are followed by the real one
+            if !(100..200).contains(&code) {
+                break (code, headers);
+            }
+        };
+        let code = ResponseCode::try_from(code).context("Upstream sent an unsupported code")?;
+        Ok((code, headers, reader))
+    }
+
+    /// Passes the response on, the lease is held until its body has been sent
+    fn respond(
+        request: &Request,
+        code: ResponseCode,
+        mut headers: Headers,
+        reader: BufReader<TcpStream>,
+        lease: Lease,
+    ) -> Result<RouteResponse> {
+        let body = if request.method() == Method::HEAD || !code.allows_body() {
+            None
+        } else if headers.contains("Transfer-Encoding") {
+            if !headers.contains_token("Transfer-Encoding", "chunked") {
+                return Err(anyhow!("Upstream sent an unsupported Transfer-Encoding"));
+            }
+            Some(BodyStream::new(
+                Leased::new(ChunkedReader::new(reader), lease),
+                None,
+            ))
+        } else if let Some(length) = headers
+            .content_length()
+            .context("Upstream sent an invalid Content-Length")?
+        {
+            let length = length as u64;
+            Some(BodyStream::new(
+                Leased::new(reader.take(length), lease),
+                Some(length),
+            ))
+        } else {
+            // We asked for the connection to be closed, so the body ends when it is
+            Some(BodyStream::new(Leased::new(reader, lease), None))
+        };
+
+        strip_hop_by_hop(&mut headers);
+        headers.remove("Content-Length");
+        let mut response = RouteResponse::from(("", code));
+        for (name, value) in headers.iter() {
+            response.headers_mut().append(name, value);
+        }
+        Ok(match body {
+            Some(body) => response.with_stream(body),
+            None => response,
+        })
+    }
+
+    /// The request as it's sent upstream, with its headers rewritten for the new hop
+    fn upstream_request(&self, request: &Request, upstream: &Upstream) -> Vec<u8> {
+        let mut headers = request.headers().clone();
+        strip_hop_by_hop(&mut headers);
+        headers.remove("Host");
+        headers.remove("Content-Length");
+        headers.remove("Expect");
+
+        let forwarded_for = request.peer_addr().map(|addr| {
+            headers
+                .get_all("X-Forwarded-For")
+                .chain([addr.ip().to_string().as_str()])
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        if let Some(forwarded_for) = forwarded_for {
+            headers.insert("X-Forwarded-For", forwarded_for);
+        }
+        if let Some(host) = request.host() {
+            headers.insert("X-Forwarded-Host", host);
+        }
+        headers.insert(
+            "X-Forwarded-Proto",
+            if request.is_secure() { "https" } else { "http" },
+        );
+
+        let body = request.body_bytes().unwrap_or_default();
+        let mut out = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            request.method(),
+            self.upstream_target(request, upstream),
+            upstream.authority()
+        );
+        if !body.is_empty() {
+            let _ = write!(out, "Content-Length: {}\r\n", body.len());
+        }
+        for (name, value) in headers.iter() {
+            let _ = write!(out, "{name}: {value}\r\n");
+        }
+        out.push_str("\r\n");
+        let mut out = out.into_bytes();
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// The origin-form target for the upstream, percent encoded again as the request's was decoded
+    fn upstream_target(&self, request: &Request, upstream: &Upstream) -> String {
+        let target = request.target();
+        let path = upstream.base_path().map_or_else(
+            || target.clone(),
+            |base| {
+                let rest = strip_prefix(&self.prefix, target).unwrap_or(target);
+                format!("{base}{rest}")
+            },
+        );
+        let mut path = encode_path(&path);
+        if let Some(query) = request.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        path
+    }
+}
+
+/// A response body that keeps its upstream's lease until it's dropped, so the upstream's request
+/// counts as in flight until its body has been sent
+struct Leased<R> {
+    reader: R,
+    _lease: Lease,
+}
+
+impl<R> Leased<R> {
+    const fn new(reader: R, lease: Lease) -> Self {
+        Self {
+            reader,
+            _lease: lease,
+        }
+    }
+}
+
+impl<R: Read> Read for Leased<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Removes the hop-by-hop headers, along with any the `Connection` header names
+fn strip_hop_by_hop(headers: &mut Headers) {
+    let named: Vec<String> = headers.get_list("Connection").map(String::from).collect();
+    for name in HOP_BY_HOP
+        .iter()
+        .copied()
+        .chain(named.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+/// Reads a status line and headers, returning the raw status code
+fn read_head<R: BufRead>(reader: &mut R) -> Result<(u16, Headers)> {
+    let mut line = String::new();
+    if reader
+        .read_line(&mut line)
+        .context("Failed to read upstream response")?
+        == 0
+    {
+        return Err(anyhow!("Upstream closed the connection without responding"));
+    }
+    let code = line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .with_context(|| format!("Upstream sent an invalid status line: {}", line.trim_end()))?;
+
+    let mut headers = Headers::new();
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .context("Failed to read upstream response")?
+            == 0
+        {
+            return Err(anyhow!(
+                "Upstream closed the connection before end of headers"
+            ));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        headers.append_line(line)?;
+    }
+    Ok((code, headers))
+}
@@ -0,0 +1,190 @@
+use std::{
+    fs, mem,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use ahash::HashMap;
+use anyhow::Result;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Config, Limits, SessionStores},
+    connection::{AsyncHandler, Handler},
+    hosts::Hosts,
+    request::Request,
+    route::{HandlerFn, RouteFuture, RouteResponse},
+};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+type Loader = dyn Fn() -> Result<Config> + Send + Sync;
+
+/// The parts of the config that can change without restarting the server
+pub struct Live {
+    pub hosts: Hosts,
+    pub limits: Limits,
+}
+
+impl Live {
+    fn build(
+        config: &Config,
+        handlers: &HashMap<&str, HandlerFn>,
+        stores: &SessionStores,
+    ) -> Result<Self> {
+        Ok(Self {
+            hosts: config.build_hosts(handlers, stores)?,
+            limits: config.limits,
+        })
+    }
+}
+
+/// Rebuilds the routes when the config file changes or on `SIGHUP`
+///
+/// Requests that are already running keep the routes they started with, and a config that fails
+/// to load leaves the current routes in place.
+pub struct Reloader {
+    path: Option<PathBuf>,
+    load: Box<Loader>,
+    handlers: HashMap<&'static str, HandlerFn>,
+    session_stores: SessionStores,
+    config: Mutex<Config>,
+    modified: Mutex<Option<SystemTime>>,
+    live: RwLock<Arc<Live>>,
+}
+
+impl Reloader {
+    /// `load` is called for every reload, so that command line arguments still take priority
+    pub fn new<L: Fn() -> Result<Config> + Send + Sync + 'static>(
+        path: Option<PathBuf>,
+        config: Config,
+        handlers: HashMap<&'static str, HandlerFn>,
+        load: L,
+    ) -> Result<Self> {
+        let session_stores = SessionStores::default();
+        let live = Live::build(&config, &handlers, &session_stores)?;
+        let modified = path.as_ref().and_then(modified);
+        Ok(Self {
+            path,
+            load: Box::new(load),
+            handlers,
+            session_stores,
+            config: Mutex::new(config),
+            modified: Mutex::new(modified),
+            live: RwLock::new(Arc::new(live)),
+        })
+    }
+
+    pub fn live(&self) -> Arc<Live> {
+        self.live.read().unwrap().clone()
+    }
+
+    pub fn reload(&self) {
+        let config = match (self.load)() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Failed to reload config, keeping the current one. Error: {err:#}");
+                return;
+            }
+        };
+        let live = match Live::build(&config, &self.handlers, &self.session_stores) {
+            Ok(live) => live,
+            Err(err) => {
+                error!("Config failed validation, keeping the current one. Error: {err:#}");
+                return;
+            }
+        };
+        *self.live.write().unwrap() = Arc::new(live);
+
+        let previous = mem::replace(&mut *self.config.lock().unwrap(), config.clone());
+        if config.runtime != previous.runtime
+            || config.threads != previous.threads
+            || config.shutdown_timeout != previous.shutdown_timeout
+            || config.listen != previous.listen
+            || config.tls != previous.tls
+            || config.logging != previous.logging
+        {
+            warn!(
+                "Changes to runtime, threads, shutdown_timeout, listen, tls or logging require a restart"
+            );
+        }
+        info!("Reloaded config");
+    }
+
+    /// Reloads whenever the config file's modification time changes
+    fn reload_if_modified(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let modified = modified(path);
+        let mut previous = self.modified.lock().unwrap();
+        if modified == *previous {
+            return;
+        }
+        *previous = modified;
+        drop(previous);
+        info!("Config file {} changed, reloading", path.display());
+        self.reload();
+    }
+
+    pub fn spawn_watcher(self: &Arc<Self>) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+            let mut signals = Signals::new([SIGHUP])?;
+            let reloader = self.clone();
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    info!("Received SIGHUP, reloading config");
+                    reloader.reload();
+                }
+            });
+        }
+
+        let reloader = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+            reloader.reload_if_modified();
+        });
+        Ok(())
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Requests are answered by the routes that are live when they arrive
+impl Handler for Reloader {
+    fn apply(&self, request: &Request) -> Result<RouteResponse> {
+        self.live().hosts.apply(request)
+    }
+
+    fn check(&self, request: &Request) -> Result<Option<RouteResponse>> {
+        self.live().hosts.check(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.live().hosts.streams_body(request)
+    }
+}
+
+impl AsyncHandler for Reloader {
+    fn apply(&self, request: Request) -> RouteFuture {
+        let live = self.live();
+        Box::pin(async move { live.hosts.apply_async(request).await })
+    }
+
+    /// A custom 404 or 405 route may block, so it's kept off the runtime's worker
+    fn check(&self, request: &Request) -> Result<Option<RouteResponse>> {
+        tokio::task::block_in_place(|| self.live().hosts.check(request))
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.live().hosts.streams_body(request)
+    }
+}
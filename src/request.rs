@@ -1,13 +1,52 @@
 use std::{
-    io::{BufRead, BufReader},
-    net::TcpStream,
+    io::{BufRead, Read, Take},
+    net::SocketAddr,
 };
 
-use ahash::{HashMap, HashMapExt};
 use anyhow::{anyhow, Context, Result};
 use derive_more::derive::{Display, FromStr, IsVariant};
 use itertools::Itertools;
-use urlencoding::decode;
+use serde::de::DeserializeOwned;
+use urlencoding::{decode, encode};
+
+use crate::{
+    body::BodyReader,
+    codes::ResponseCode,
+    cookie::CookieJar,
+    form::{Form, Multipart, MultipartLimits},
+    headers::Headers,
+    session::Session,
+};
+
+/// Errors that should be reported to the client with something more specific than a 400
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error("Request body of {length} bytes exceeds the limit of {limit} bytes")]
+    BodyTooLarge { length: usize, limit: usize },
+    #[error("Request headers exceed the limit of {limit} bytes")]
+    HeadersTooLarge { limit: usize },
+    #[error("Request has an expectation we can't meet: {0}")]
+    UnsupportedExpectation(String),
+    #[error("Request body should be {expected}")]
+    UnsupportedMediaType { expected: &'static str },
+    #[error("Multipart field {name} exceeds the limit of {limit} bytes")]
+    PartTooLarge { name: String, limit: usize },
+    #[error("Multipart body exceeds the limit of {limit} bytes")]
+    MultipartTooLarge { limit: usize },
+}
+
+impl RequestError {
+    pub const fn code(&self) -> ResponseCode {
+        match self {
+            Self::BodyTooLarge { .. }
+            | Self::PartTooLarge { .. }
+            | Self::MultipartTooLarge { .. } => ResponseCode::Content_Too_Large,
+            Self::HeadersTooLarge { .. } => ResponseCode::Request_Header_Fields_Too_Large,
+            Self::UnsupportedExpectation(_) => ResponseCode::Expectation_Failed,
+            Self::UnsupportedMediaType { .. } => ResponseCode::Unsupported_Media_Type,
+        }
+    }
+}
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr, Hash, IsVariant)]
@@ -23,41 +62,122 @@ pub enum Method {
     PATCH,
 }
 
+impl Method {
+    pub const ALL: [Self; 9] = [
+        Self::GET,
+        Self::HEAD,
+        Self::PUT,
+        Self::POST,
+        Self::DELETE,
+        Self::CONNECT,
+        Self::OPTIONS,
+        Self::TRACE,
+        Self::PATCH,
+    ];
+}
+
 impl From<Method> for Vec<Method> {
     fn from(val: Method) -> Self {
         vec![val]
     }
 }
 
+/// The four forms a request-target can take, as defined in RFC 9112 section 3.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, IsVariant)]
+pub enum TargetForm {
+    /// `/path?query`, used for most requests
+    Origin,
+    /// `http://host/path?query`, used for requests made to a proxy
+    Absolute,
+    /// `host:port`, only used with CONNECT
+    Authority,
+    /// `*`, only used with a server wide OPTIONS request
+    Asterisk,
+}
+
 #[derive(Debug, Clone)]
 pub struct Request {
     method: Method,
     target: String,
+    target_form: TargetForm,
+    query: Option<String>,
+    scheme: Option<String>,
+    authority: Option<String>,
     version: String,
-    headers: HashMap<String, String>,
-    body: Option<String>,
+    headers: Headers,
+    body: Option<Vec<u8>>,
+    /// Set instead of `body` when it's left on the connection for the handler to stream
+    body_reader: Option<BodyReader>,
+    peer_addr: Option<SocketAddr>,
+    secure: bool,
+    /// Filled in from the target by a route with `{name}` segments
+    path_params: Vec<(String, String)>,
+    /// Attached by the routes when they have sessions enabled
+    session: Option<Session>,
 }
 
 impl Request {
-    pub fn parse(mut reader: BufReader<&mut TcpStream>) -> Result<Self> {
+    /// Parses the start-line and headers, leaving the body unread
+    ///
+    /// No more than `max_header_size` bytes are read, so a client can't grow the head without end
+    pub fn parse_head<R: BufRead>(reader: R, max_header_size: usize) -> Result<Self> {
+        let mut reader = reader.take(max_header_size as u64);
         let mut line = String::new();
-        reader.read_line(&mut line)?;
+        read_head_line(&mut reader, &mut line, max_header_size)?;
         let (method, target, version) = line
             .split_whitespace()
             .take(3)
             .collect_tuple()
             .context("Failed to parse start-line")?; // Error for the collect_touple
         let method: Method = method.parse().context("Failed to parse HTTP Method")?;
-        let target = if target.is_empty() {
-            String::from("/")
+        let (target_form, scheme, authority, target) = if target == "*" {
+            if !method.is_options() {
+                return Err(anyhow!(
+                    "Asterisk target is only valid for OPTIONS requests"
+                ));
+            }
+            (TargetForm::Asterisk, None, None, target)
         } else if target.starts_with('/') {
+            (TargetForm::Origin, None, None, target)
+        } else if method.is_connect() {
+            if target.contains('/') || !target.contains(':') {
+                return Err(anyhow!("CONNECT target must be in the form host:port"));
+            }
+            (TargetForm::Authority, None, Some(target.to_string()), "")
+        } else if let Some((scheme, rest)) = target.split_once("://") {
+            if scheme.is_empty() || !scheme.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(anyhow!("Invalid target scheme: {scheme}"));
+            }
+            let (authority, path) = rest
+                .find(['/', '?'])
+                .map_or((rest, "/"), |index| rest.split_at(index));
+            if authority.is_empty() {
+                return Err(anyhow!("Absolute target is missing an authority"));
+            }
+            (
+                TargetForm::Absolute,
+                Some(scheme.to_ascii_lowercase()),
+                Some(authority.to_string()),
+                path,
+            )
+        } else {
+            return Err(anyhow!("Invalid request target: {target}"));
+        };
+        let (path, query) = target
+            .split_once('?')
+            .map_or((target, None), |(path, query)| {
+                (path, Some(query.to_string()))
+            });
+        let target = if target_form.is_authority() {
+            String::new()
+        } else if path.is_empty() {
+            String::from("/")
+        } else {
             // NOTE: Looks like the decode function here resolves path travesal at this point by
             // resolving the path now
             // I'll leave the code for preventing path traversal in place in the routes apply
             // function just in case. I'd rather not rely on this being here
-            decode(target)?.into_owned()
-        } else {
-            return Err(anyhow!("Target value must start with '/'"));
+            decode(path)?.into_owned()
         };
         let version = if version.starts_with("HTTP/") {
             version.to_string()
@@ -65,44 +185,140 @@ impl Request {
             return Err(anyhow!("Invalid HTTP version"));
         };
 
-        let mut in_headers = true;
-        let mut body: Option<String> = None;
-        let mut headers = HashMap::new();
-        for line in reader
-            .lines() // FIXME: Because of the code bellow the body is never captured
-            .take_while(|line| line.as_ref().is_ok_and(|line| !line.is_empty()))
-        {
-            let line = line?;
-            if line.trim().is_empty() {
-                in_headers = false;
-                continue;
+        let mut headers = Headers::new();
+        loop {
+            let mut line = String::new();
+            if read_head_line(&mut reader, &mut line, max_header_size)? == 0 {
+                return Err(anyhow!("Connection closed before end of headers"));
             }
-
-            if in_headers {
-                let (name, content) = line
-                    .trim()
-                    .split_once(':')
-                    .context("Failed to parse header")?;
-                let name = name.to_lowercase();
-                let content = content.trim().to_string();
-                headers.insert(name, content);
-            } else if let Some(body) = &mut body {
-                body.push_str(&line);
-            } else if !line.trim().is_empty() {
-                body = Some(line.to_string());
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
             }
+            headers.append_line(line)?;
         }
-        //debug!("Finished reading request body");
 
         Ok(Self {
             method,
             target,
+            target_form,
+            query,
+            scheme,
+            authority,
             version,
             headers,
-            body,
+            body: None,
+            body_reader: None,
+            peer_addr: None,
+            secure: false,
+            path_params: Vec::new(),
+            session: None,
         })
     }
 
+    /// The length of the body following the head, only Content-Length framing is supported
+    pub fn body_length(&self, max_body_size: usize) -> Result<Option<usize>> {
+        if self.headers.contains("Transfer-Encoding") {
+            // We can't tell where the body ends, so there's no safe way to keep reading after this
+            return Err(anyhow!(
+                "Transfer-Encoding request bodies are not supported"
+            ));
+        }
+        match self.headers.content_length()? {
+            Some(0) | None => Ok(None),
+            Some(length) if length > max_body_size => Err(RequestError::BodyTooLarge {
+                length,
+                limit: max_body_size,
+            }
+            .into()),
+            Some(length) => Ok(Some(length)),
+        }
+    }
+
+    /// Whether the client is waiting to be sent `100 Continue` before it sends the body, HTTP/1.0
+    /// clients don't know about interim responses so their expectations are ignored
+    pub fn expects_continue(&self) -> Result<bool> {
+        if self.version == "HTTP/1.0" {
+            return Ok(false);
+        }
+        let mut expects_continue = false;
+        for expectation in self.headers.get_list("Expect") {
+            if expectation.eq_ignore_ascii_case("100-continue") {
+                expects_continue = true;
+            } else {
+                return Err(RequestError::UnsupportedExpectation(expectation.to_string()).into());
+            }
+        }
+        Ok(expects_continue)
+    }
+
+    /// Whether the client wants the connection kept open after this request, which HTTP/1.0
+    /// clients have to opt in to
+    pub fn wants_keep_alive(&self) -> bool {
+        if self.version == "HTTP/1.0" {
+            self.headers.contains_token("Connection", "keep-alive")
+        } else {
+            !self.headers.contains_token("Connection", "close")
+        }
+    }
+
+    /// Records where the request came from, as the parser only sees its bytes
+    pub const fn set_connection(&mut self, peer_addr: Option<SocketAddr>, secure: bool) {
+        self.peer_addr = peer_addr;
+        self.secure = secure;
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = Some(body);
+    }
+
+    pub fn set_body_reader(&mut self, reader: BodyReader) {
+        self.body_reader = Some(reader);
+    }
+
+    /// Whether the handler left some of a streamed body on the connection
+    pub fn has_unread_body(&self) -> bool {
+        self.body_reader
+            .as_ref()
+            .is_some_and(|reader| !reader.is_done())
+    }
+
+    /// The client's address, clients connected through a Unix socket don't have one
+    pub const fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn set_path_params(&mut self, params: Vec<(String, String)>) {
+        self.path_params = params;
+    }
+
+    /// The segment of the target matched by `{name}` in the route's target
+    #[allow(dead_code)]
+    pub fn path_param(&self, name: &str) -> Option<&str> {
+        self.path_params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn path_params(&self) -> &[(String, String)] {
+        &self.path_params
+    }
+
+    pub fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    /// The client's session, `None` unless the site has sessions enabled
+    pub const fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Whether the request arrived over TLS
+    pub const fn is_secure(&self) -> bool {
+        self.secure
+    }
+
     pub const fn method(&self) -> Method {
         self.method
     }
@@ -115,27 +331,191 @@ impl Request {
         self.target.trim_start_matches('/')
     }
 
+    pub const fn target_form(&self) -> TargetForm {
+        self.target_form
+    }
+
+    /// The raw, still percent encoded, query string without the leading '?'
+    pub const fn query(&self) -> Option<&String> {
+        self.query.as_ref()
+    }
+
+    /// The scheme from an absolute-form target, always lowercase
+    pub const fn scheme(&self) -> Option<&String> {
+        self.scheme.as_ref()
+    }
+
+    /// The authority from an absolute-form or authority-form target
+    pub const fn authority(&self) -> Option<&String> {
+        self.authority.as_ref()
+    }
+
+    /// The host this request was made to
+    ///
+    /// RFC 9112 requires that the authority of an absolute-form target takes priority over the
+    /// `Host` header
+    pub fn host(&self) -> Option<&str> {
+        self.authority
+            .as_deref()
+            .or_else(|| self.headers.get("Host"))
+    }
+
     pub const fn version(&self) -> &String {
         &self.version
     }
 
-    pub const fn headers(&self) -> &HashMap<String, String> {
+    pub const fn headers(&self) -> &Headers {
         &self.headers
     }
 
-    pub const fn body(&self) -> Option<&String> {
-        self.body.as_ref()
+    /// The body as text, which is `None` if it isn't valid UTF-8 as well as when there's no body
+    pub fn body(&self) -> Option<&str> {
+        self.body
+            .as_deref()
+            .and_then(|body| std::str::from_utf8(body).ok())
+    }
+
+    /// `None` for a multipart body that's been left for the handler to stream, see `multipart`
+    pub fn body_bytes(&self) -> Option<&[u8]> {
+        self.body.as_deref()
+    }
+
+    /// Deserializes a JSON body, other content types are a `RequestError` so they can be answered
+    /// with a 415
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        let is_json = self.headers.content_type().is_some_and(|content_type| {
+            content_type.essence() == "application/json"
+                || content_type.essence().ends_with("+json")
+        });
+        if !is_json {
+            return Err(RequestError::UnsupportedMediaType {
+                expected: "application/json",
+            }
+            .into());
+        }
+        serde_json::from_slice(self.body_bytes().unwrap_or_default()).context("Invalid JSON body")
+    }
+
+    /// The cookies sent with the request, from every `Cookie` header
+    pub fn cookies(&self) -> CookieJar {
+        CookieJar::parse(&self.headers)
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body, other content types are a
+    /// `RequestError` so they can be answered with a 415
+    pub fn form(&self) -> Result<Form> {
+        if !self.has_content_type("application/x-www-form-urlencoded") {
+            return Err(RequestError::UnsupportedMediaType {
+                expected: "application/x-www-form-urlencoded",
+            }
+            .into());
+        }
+        let body = std::str::from_utf8(self.body_bytes().unwrap_or_default())
+            .context("Form body is not valid UTF-8")?;
+        Form::parse(body)
+    }
+
+    /// Reads a `multipart/form-data` body a part at a time, other content types are a
+    /// `RequestError` so they can be answered with a 415
+    ///
+    /// Over HTTP/1.1 the body is read from the connection as the parts are, rather than before the
+    /// handler runs, so it's limited by `limits` instead of `max_body_size`. HTTP/2 bodies are
+    /// still read up front. Reading blocks, so async handlers should do it in `block_in_place`
+    pub fn multipart(
+        &self,
+        limits: MultipartLimits,
+    ) -> Result<Multipart<Box<dyn Read + Send + '_>>> {
+        let content_type = self
+            .headers
+            .content_type()
+            .filter(|content_type| content_type.essence() == "multipart/form-data")
+            .ok_or(RequestError::UnsupportedMediaType {
+                expected: "multipart/form-data",
+            })?;
+        let boundary = content_type
+            .param("boundary")
+            .context("Multipart body is missing its boundary")?;
+        // There's no point reading a body that's already said it's too large
+        if self
+            .headers
+            .content_length()?
+            .is_some_and(|length| length > limits.max_total_size)
+        {
+            return Err(RequestError::MultipartTooLarge {
+                limit: limits.max_total_size,
+            }
+            .into());
+        }
+        let reader: Box<dyn Read + Send + '_> = match &self.body_reader {
+            Some(reader) => Box::new(reader.clone()),
+            None => Box::new(self.body_bytes().unwrap_or_default()),
+        };
+        Multipart::new(reader, boundary, limits)
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.has_content_type("multipart/form-data")
+    }
+
+    fn has_content_type(&self, essence: &str) -> bool {
+        self.headers
+            .content_type()
+            .is_some_and(|content_type| content_type.essence() == essence)
     }
 
     pub fn as_string(&self) -> String {
-        let mut out = format!("{} {} {}\n", self.method(), self.target(), self.version());
+        let mut out = format!("{} ", self.method());
+        if let (Some(scheme), Some(authority)) = (self.scheme(), self.authority()) {
+            out.push_str(scheme);
+            out.push_str("://");
+            out.push_str(authority);
+        } else if let Some(authority) = self.authority() {
+            out.push_str(authority);
+        }
+        out.push_str(self.target());
+        if let Some(query) = self.query() {
+            out.push('?');
+            out.push_str(query);
+        }
+        out.push(' ');
+        out.push_str(self.version());
+        out.push('\n');
         for (key, val) in self.headers() {
-            out.push_str(&format!("{key}: {val}\n"));
+            out.push_str(key);
+            out.push_str(": ");
+            out.push_str(val);
+            out.push('\n');
         }
-        if let Some(body) = self.body() {
-            out.push_str(body);
+        if let Some(body) = self.body_bytes() {
+            out.push_str(&String::from_utf8_lossy(body));
         }
 
         out
     }
 }
+
+/// Reads a line of the head, a line that's cut short by the limit means the head is too large
+fn read_head_line<R: BufRead>(
+    reader: &mut Take<R>,
+    line: &mut String,
+    max_header_size: usize,
+) -> Result<usize> {
+    let read = reader.read_line(line)?;
+    if reader.limit() == 0 && !line.ends_with('\n') {
+        return Err(RequestError::HeadersTooLarge {
+            limit: max_header_size,
+        }
+        .into());
+    }
+    Ok(read)
+}
+
+/// Percent encodes each segment of a decoded path, leaving the slashes between them in place
+pub fn encode_path(path: &str) -> String {
+    let path = path.split('/').map(encode).join("/");
+    if path.is_empty() {
+        String::from("/")
+    } else {
+        path
+    }
+}
@@ -8,11 +8,14 @@ pub struct StatusLine {
 }
 
 impl StatusLine {
-    pub fn new(code: ResponseCode) -> Self {
-        Self {
-            version: SUPPORTED_HTTP_VERSION,
-            code,
-        }
+    /// Replies in the request's version when it's HTTP/1.0, otherwise in the version we support,
+    /// which is also used when there's no request to go by
+    pub fn new(code: ResponseCode, request_version: Option<&str>) -> Self {
+        let version = match request_version {
+            Some("HTTP/1.0") => "HTTP/1.0",
+            _ => SUPPORTED_HTTP_VERSION,
+        };
+        Self { version, code }
     }
 }
 
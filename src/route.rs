@@ -1,32 +1,63 @@
 use std::{
     fs,
+    future::Future,
+    io,
     path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
 use ahash::HashMap;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use serde::Serialize;
+use threadpool::ThreadPool;
+use tokio::runtime::{self, Handle, Runtime};
 use tracing::error;
 
 use crate::{
+    body::BodyStream,
+    cgi::Cgi,
     codes::ResponseCode,
-    request::{Method, Request},
+    cookie::Cookie,
+    extract::TypedFn,
+    headers::Headers,
+    proxy::Proxy,
+    request::{Method, Request, RequestError, TargetForm},
+    session::{session_failed, Sessions},
+    stream::Socket,
+    websocket::{self, WebSocket, WebSocketFn},
 };
 
 #[allow(clippy::module_name_repetitions)]
 pub struct RouteResponse {
     content: String,
     response_code: ResponseCode,
+    headers: Headers,
     require_logging: bool,
     logging_context: Option<String>,
+    stream: Option<BodyStream>,
+    upgrade: Option<Upgrade>,
 }
 
+/// Takes over the connection once the response has been sent, for protocols switched to with
+/// `Upgrade`
+pub type Upgrade = Box<dyn FnOnce(Box<dyn Socket>) + Send>;
+
 impl RouteResponse {
     pub const fn new_ok(content: String, response_code: ResponseCode) -> Self {
         Self {
             content,
             response_code,
+            headers: Headers::new(),
             require_logging: false,
             logging_context: None,
+            stream: None,
+            upgrade: None,
         }
     }
 
@@ -38,8 +69,11 @@ impl RouteResponse {
         Self {
             content,
             response_code,
+            headers: Headers::new(),
             require_logging: true,
             logging_context,
+            stream: None,
+            upgrade: None,
         }
     }
 
@@ -51,6 +85,51 @@ impl RouteResponse {
         &self.content
     }
 
+    pub const fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub const fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Sets a response header, replacing any existing values for it
+    #[must_use]
+    pub fn with_header<A: Into<String>, B: Into<String>>(mut self, name: A, value: B) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a `Set-Cookie` header, alongside any cookies already set, unless the cookie fails
+    /// `Cookie::validate`
+    pub fn with_cookie(mut self, cookie: &Cookie) -> Result<Self> {
+        cookie.validate()?;
+        self.headers.append("Set-Cookie", cookie.to_string());
+        Ok(self)
+    }
+
+    /// Sends the body from a reader instead of `content`, as it's read
+    #[must_use]
+    pub fn with_stream(mut self, stream: BodyStream) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    pub const fn take_stream(&mut self) -> Option<BodyStream> {
+        self.stream.take()
+    }
+
+    /// Hands the connection to `upgrade` after this response, which should be a 101
+    #[must_use]
+    pub fn with_upgrade(mut self, upgrade: Upgrade) -> Self {
+        self.upgrade = Some(upgrade);
+        self
+    }
+
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
     pub const fn should_log(&self) -> bool {
         self.require_logging
     }
@@ -58,6 +137,34 @@ impl RouteResponse {
     pub const fn context(&self) -> Option<&String> {
         self.logging_context.as_ref()
     }
+
+    /// A 200 with the value serialized as its body, sent as `application/json`
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Self> {
+        let body = serde_json::to_string(value).context("Failed to serialize JSON response")?;
+        Ok(Self::from((body, ResponseCode::Ok)).with_header("Content-Type", "application/json"))
+    }
+
+    /// An error in the format every JSON route uses,
+    /// `{"error": {"status": 404, "reason": "Not Found", "message": "..."}}`
+    pub fn json_error(code: ResponseCode, message: &str) -> Self {
+        let body = serde_json::json!({
+            "error": {
+                "status": code as u16,
+                "reason": code.reason(),
+                "message": message,
+            }
+        });
+        Self::from((body, code)).with_header("Content-Type", "application/json")
+    }
+
+    /// The JSON error for a request body that couldn't be read, ie: by `Request::json`, using the
+    /// status from its `RequestError` or a 400
+    pub fn json_rejection(err: &anyhow::Error) -> Self {
+        let code = err
+            .downcast_ref::<RequestError>()
+            .map_or(ResponseCode::Bad_Request, RequestError::code);
+        Self::json_error(code, &format!("{err:#}"))
+    }
 }
 
 impl<S: ToString> From<(S, ResponseCode)> for RouteResponse {
@@ -103,15 +210,52 @@ impl<S: ToString> From<(S, ResponseCode, &str)> for RouteResponse {
 #[allow(clippy::module_name_repetitions)]
 pub type FnRoute = fn(&Request) -> Result<RouteResponse>;
 
+#[allow(clippy::module_name_repetitions)]
+pub type RouteFuture = Pin<Box<dyn Future<Output = Result<RouteResponse>> + Send>>;
+
+/// Async handlers take the request by value so the future they return can outlive the connection's
+/// borrow of it
+#[allow(clippy::module_name_repetitions)]
+pub type AsyncFnRoute = fn(Request) -> RouteFuture;
+
+/// A handler built into the server that the config file can refer to by name
+#[derive(Debug, Clone)]
+pub enum HandlerFn {
+    Sync(FnRoute),
+    Async(AsyncFnRoute),
+    WebSocket(WebSocketFn),
+    /// Takes typed parameters that are extracted from the request before it's called
+    Typed(TypedFn),
+}
+
 #[derive(Debug, Clone)]
 pub enum Route {
     Static(String, Option<ResponseCode>),
     Plain(String, Option<ResponseCode>),
-    Dynamic(FnRoute),
+    Redirect(String, Option<ResponseCode>),
+    /// Handlers taking longer than their timeout, or the default for the routes, get a 504
+    Dynamic(FnRoute, Option<Duration>),
+    Async(AsyncFnRoute, Option<Duration>),
+    Typed(TypedFn, Option<Duration>),
+    /// Matches every method for its prefix and anything below it
+    Proxy(Proxy),
+    /// Matches every method for its prefix and anything below it
+    Cgi(Cgi),
+    /// Timeouts don't apply, the handler holds the connection for as long as it likes
+    WebSocket(WebSocketFn),
 }
 
 impl Route {
-    fn apply(&self, request: &Request) -> Result<RouteResponse> {
+    /// The prefix for routes that match a target and everything below it
+    fn prefix(&self) -> Option<&str> {
+        match self {
+            Self::Proxy(proxy) => Some(proxy.prefix()),
+            Self::Cgi(cgi) => Some(cgi.prefix()),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, request: &Request, default_timeout: Option<Duration>) -> Result<RouteResponse> {
         match self {
             Self::Static(path, code) => {
                 Ok((fs::read_to_string(path)?, code.unwrap_or(ResponseCode::Ok)).into())
@@ -119,7 +263,42 @@ impl Route {
             Self::Plain(content, code) => {
                 Ok((content.clone(), code.unwrap_or(ResponseCode::Ok)).into())
             }
-            Self::Dynamic(f) => f(request),
+            Self::Redirect(location, code) => Ok(RouteResponse::from((
+                "",
+                code.unwrap_or(ResponseCode::Found),
+            ))
+            .with_header("Location", location.clone())),
+            Self::Dynamic(f, timeout) => timeout.or(default_timeout).map_or_else(
+                || f(request),
+                |timeout| apply_with_timeout(*f, request, timeout),
+            ),
+            Self::Typed(f, timeout) => timeout.or(default_timeout).map_or_else(
+                || f.call(request),
+                |timeout| {
+                    let f = f.clone();
+                    apply_with_timeout(move |request| f.call(request), request, timeout)
+                },
+            ),
+            // Only reached in the threaded runtime, or from a handler that's already blocking
+            Self::Async(f, timeout) => {
+                let future = with_timeout(f(request.clone()), request, timeout.or(default_timeout));
+                match Handle::try_current() {
+                    Ok(handle) => handle.block_on(future),
+                    Err(_) => blocking_runtime().block_on(future),
+                }
+            }
+            Self::Proxy(proxy) => Ok(proxy.forward(request)),
+            Self::Cgi(cgi) => Ok(cgi.run(request)),
+            Self::WebSocket(handler) => {
+                let response = websocket::handshake(request);
+                if response.code() != ResponseCode::Switching_Protocols {
+                    return Ok(response);
+                }
+                let (request, handler) = (request.clone(), *handler);
+                Ok(response.with_upgrade(Box::new(move |socket| {
+                    WebSocket::serve(socket, &request, handler);
+                })))
+            }
         }
     }
 }
@@ -131,6 +310,12 @@ pub struct Routes {
     four_oh_five: Option<Route>,
     static_dir: Option<PathBuf>,
     auto_index: bool,
+    handler_timeout: Option<Duration>,
+    /// Routes with a prefix, longest first so the most specific one wins
+    prefixed: Vec<Route>,
+    /// Handlers with `{name}` segments in their target, the first one added that matches wins
+    patterns: Vec<Pattern>,
+    sessions: Option<Sessions>,
 }
 
 #[allow(dead_code)]
@@ -172,20 +357,80 @@ impl Routes {
         }
     }
 
+    pub fn add_redirect<A: Into<String>, B: Into<String>>(
+        &mut self,
+        target: A,
+        location: B,
+        code: Option<ResponseCode>,
+    ) -> Result<()> {
+        let target: String = target.into();
+        if let std::collections::hash_map::Entry::Vacant(e) = self.map.entry((Method::GET, target))
+        {
+            e.insert(Route::Redirect(location.into(), code));
+            Ok(())
+        } else {
+            // TODO: Implement custom error type to handle this
+            Err(anyhow!("Target already exists"))
+        }
+    }
+
     pub fn add_dynamic<A: Into<String>, M: Into<Vec<Method>>>(
         &mut self,
         target: A,
         method: M,
         f: FnRoute,
+    ) -> Result<()> {
+        self.add_handler(target, method, HandlerFn::Sync(f), None)
+    }
+
+    pub fn add_async<A: Into<String>, M: Into<Vec<Method>>>(
+        &mut self,
+        target: A,
+        method: M,
+        f: AsyncFnRoute,
+    ) -> Result<()> {
+        self.add_handler(target, method, HandlerFn::Async(f), None)
+    }
+
+    /// Adds a sync or async handler, `timeout` overrides the default set for these routes
+    pub fn add_handler<A: Into<String>, M: Into<Vec<Method>>>(
+        &mut self,
+        target: A,
+        method: M,
+        handler: HandlerFn,
+        timeout: Option<Duration>,
     ) -> Result<()> {
         let target: String = target.into();
         // TODO: Verify that the provided target is valid
-
+        let route = match handler {
+            HandlerFn::Sync(f) => Route::Dynamic(f, timeout),
+            HandlerFn::Async(f) => Route::Async(f, timeout),
+            HandlerFn::WebSocket(f) => Route::WebSocket(f),
+            HandlerFn::Typed(f) => Route::Typed(f, timeout),
+        };
+
+        if let Some(segments) = Pattern::parse(&target)? {
+            for method in method.into() {
+                if self
+                    .patterns
+                    .iter()
+                    .any(|pattern| pattern.method == method && pattern.segments == segments)
+                {
+                    return Err(anyhow!("Target already exists"));
+                }
+                self.patterns.push(Pattern {
+                    method,
+                    segments: segments.clone(),
+                    route: route.clone(),
+                });
+            }
+            return Ok(());
+        }
         for method in method.into() {
             if let std::collections::hash_map::Entry::Vacant(e) =
                 self.map.entry((method, target.clone()))
             {
-                e.insert(Route::Dynamic(f));
+                e.insert(route.clone());
             } else {
                 // TODO: Implement custom error type to handle this
                 return Err(anyhow!("Target already exists"));
@@ -194,6 +439,58 @@ impl Routes {
         Ok(())
     }
 
+    pub fn add_proxy(&mut self, proxy: Proxy) -> Result<()> {
+        self.add_prefixed(Route::Proxy(proxy))
+    }
+
+    pub fn add_cgi(&mut self, cgi: Cgi) -> Result<()> {
+        self.add_prefixed(Route::Cgi(cgi))
+    }
+
+    fn add_prefixed(&mut self, route: Route) -> Result<()> {
+        if self
+            .prefixed
+            .iter()
+            .any(|existing| existing.prefix() == route.prefix())
+        {
+            return Err(anyhow!("Target already exists"));
+        }
+        self.prefixed.push(route);
+        self.prefixed
+            .sort_by_key(|route| std::cmp::Reverse(route.prefix().map_or(0, str::len)));
+        Ok(())
+    }
+
+    /// The first pattern matching the request, along with the parameters it captured
+    fn pattern_route(&self, request: &Request) -> Option<(&Route, Vec<(String, String)>)> {
+        self.patterns
+            .iter()
+            .filter(|pattern| pattern.method == request.method())
+            .find_map(|pattern| {
+                pattern
+                    .captures(request.target())
+                    .map(|params| (&pattern.route, params))
+            })
+    }
+
+    fn prefixed_route(&self, target: &str) -> Option<&Route> {
+        self.prefixed.iter().find(|route| {
+            route
+                .prefix()
+                .is_some_and(|prefix| strip_prefix(prefix, target).is_some())
+        })
+    }
+
+    /// How long handlers may run before the client gets a 504, unless the route sets its own
+    pub const fn set_handler_timeout(&mut self, timeout: Option<Duration>) {
+        self.handler_timeout = timeout;
+    }
+
+    /// Gives every request a session, which is saved after its handler returns
+    pub fn set_sessions(&mut self, sessions: Sessions) {
+        self.sessions = Some(sessions);
+    }
+
     pub fn set_404(&mut self, route: Route) {
         self.four_oh_four = Some(route);
     }
@@ -206,16 +503,40 @@ impl Routes {
         self.static_dir = Some(path.into());
     }
 
-    pub fn set_auto_index(&mut self, enabled: bool) {
+    pub const fn set_auto_index(&mut self, enabled: bool) {
         self.auto_index = enabled;
     }
 
     pub fn apply(&self, request: &Request) -> Result<RouteResponse> {
+        let Some(sessions) = &self.sessions else {
+            return self.route(request);
+        };
+        let mut request = request.clone();
+        let session = match sessions.start(&mut request) {
+            Ok(session) => session,
+            Err(err) => return Ok(session_failed(&err)),
+        };
+        let response = self.route(&request)?;
+        Ok(sessions
+            .finish(&session, response)
+            .unwrap_or_else(|err| session_failed(&err)))
+    }
+
+    fn route(&self, request: &Request) -> Result<RouteResponse> {
         // TODO: Rewrite this to use a fail fast methodology
         // TODO: Handle wildcard targets
         // TODO: This clone is not ideal
-        if let Some(route) = self.map.get(&(request.method(), request.target().clone())) {
-            route.apply(request)
+        if request.target_form() == TargetForm::Asterisk {
+            // The only valid use of '*' is a server wide OPTIONS request
+            Ok(self.server_options())
+        } else if let Some(route) = self.map.get(&(request.method(), request.target().clone())) {
+            route.apply(request, self.handler_timeout)
+        } else if let Some((route, params)) = self.pattern_route(request) {
+            let mut request = request.clone();
+            request.set_path_params(params);
+            route.apply(&request, self.handler_timeout)
+        } else if let Some(route) = self.prefixed_route(request.target()) {
+            route.apply(request, self.handler_timeout)
         } else if let Some(dir) = self.static_dir.as_ref() {
             // First we need to confirm this is actually the Route the user wants
             if let Some(target) = request.target_as_path().strip_prefix(dir.to_str().unwrap()) {
@@ -269,6 +590,117 @@ impl Routes {
         }
     }
 
+    /// Works out from the head alone whether the request would reach a route, so a client waiting
+    /// to send its body can be turned away before it does. Returns the response to send it if so
+    pub fn check(&self, request: &Request) -> Result<Option<RouteResponse>> {
+        if request.target_form() == TargetForm::Asterisk
+            || self
+                .map
+                .contains_key(&(request.method(), request.target().clone()))
+            || self.pattern_route(request).is_some()
+            || self.prefixed_route(request.target()).is_some()
+        {
+            return Ok(None);
+        }
+        let in_static_dir = self
+            .static_dir
+            .as_ref()
+            .is_some_and(|dir| request.target_as_path().starts_with(dir.to_str().unwrap()));
+        if !in_static_dir {
+            self.four_oh_four(request).map(Some)
+        } else if !request.method().is_get() {
+            self.four_oh_five(request, Method::GET).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether the request's body should be left on the connection for its handler to stream
+    ///
+    /// That's only done for multipart bodies sent to handlers, which can read them with
+    /// `Request::multipart`, everything else, ie: a proxy or CGI program, gets the whole body
+    pub fn streams_body(&self, request: &Request) -> bool {
+        if !request.is_multipart() {
+            return false;
+        }
+        let route = self
+            .map
+            .get(&(request.method(), request.target().clone()))
+            .or_else(|| self.pattern_route(request).map(|(route, _)| route));
+        matches!(
+            route,
+            Some(Route::Dynamic(..) | Route::Async(..) | Route::Typed(..))
+        )
+    }
+
+    /// Applies the routes from within the async runtime
+    ///
+    /// Async handlers are awaited directly, everything else may block on the file system or a
+    /// sync handler, so it's moved off the runtime's worker with `block_in_place`
+    pub async fn apply_async(&self, mut request: Request) -> Result<RouteResponse> {
+        let Some(sessions) = &self.sessions else {
+            return self.route_async(request).await;
+        };
+        // Stores may block on the file system, like the sync routes
+        let session = match tokio::task::block_in_place(|| sessions.start(&mut request)) {
+            Ok(session) => session,
+            Err(err) => return Ok(session_failed(&err)),
+        };
+        let response = self.route_async(request).await?;
+        Ok(tokio::task::block_in_place(|| {
+            sessions
+                .finish(&session, response)
+                .unwrap_or_else(|err| session_failed(&err))
+        }))
+    }
+
+    async fn route_async(&self, mut request: Request) -> Result<RouteResponse> {
+        let route = self
+            .map
+            .get(&(request.method(), request.target().clone()))
+            .or_else(|| {
+                self.pattern_route(&request).map(|(route, params)| {
+                    request.set_path_params(params);
+                    route
+                })
+            });
+        if let Some(Route::Async(f, timeout)) = route {
+            let timeout = timeout.or(self.handler_timeout);
+            return with_timeout(f(request.clone()), &request, timeout).await;
+        }
+        tokio::task::block_in_place(|| self.route(&request))
+    }
+
+    /// Lists every method any route on this server will accept
+    pub fn allowed_methods(&self) -> Vec<Method> {
+        let mut methods: Vec<Method> = self
+            .map
+            .keys()
+            .map(|(method, _)| *method)
+            .chain(self.patterns.iter().map(|pattern| pattern.method))
+            .collect();
+        if self.static_dir.is_some() {
+            methods.push(Method::GET);
+        }
+        // Proxy and CGI routes pass every method on, but a CONNECT target never matches a prefix
+        if !self.prefixed.is_empty() {
+            methods.extend(
+                Method::ALL
+                    .into_iter()
+                    .filter(|method| !method.is_connect()),
+            );
+        }
+        methods.push(Method::OPTIONS);
+        methods.sort_by_key(|method| *method as u8);
+        methods.dedup();
+        methods
+    }
+
+    fn server_options(&self) -> RouteResponse {
+        RouteResponse::from(("", ResponseCode::Ok))
+            .with_header("Allow", self.allowed_methods().iter().join(", "))
+    }
+
     fn auto_index(&self, request: &Request, path: &Path) -> Result<RouteResponse> {
         if !self.auto_index || !path.exists() {
             self.four_oh_four(request)
@@ -290,7 +722,7 @@ impl Routes {
     pub fn four_oh_four(&self, request: &Request) -> Result<RouteResponse> {
         self.four_oh_four.as_ref().map_or(
             Ok(("404 Not Found", ResponseCode::Not_Found).into()),
-            |route| route.apply(request),
+            |route| route.apply(request, self.handler_timeout),
         )
     }
 
@@ -308,7 +740,208 @@ impl Routes {
                 )
                     .into())
             },
-            |route| route.apply(request),
+            |route| route.apply(request, self.handler_timeout),
         )
     }
 }
+
+/// A handler target with `{name}` segments, each of which matches any one non-empty segment
+#[derive(Debug, Clone)]
+struct Pattern {
+    method: Method,
+    segments: Vec<Segment>,
+    route: Route,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+impl Pattern {
+    /// Splits the target into segments, returning `None` if it doesn't have any parameters
+    fn parse(target: &str) -> Result<Option<Vec<Segment>>> {
+        if !target.contains('{') {
+            return Ok(None);
+        }
+        target
+            .split('/')
+            .map(|segment| {
+                match segment
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.strip_suffix('}'))
+                {
+                    Some(name) if !name.is_empty() && !name.contains(['{', '}']) => {
+                        Ok(Segment::Param(name.to_string()))
+                    }
+                    None if !segment.contains(['{', '}']) => {
+                        Ok(Segment::Literal(segment.to_string()))
+                    }
+                    _ => Err(anyhow!("Invalid parameter in target segment: {segment}")),
+                }
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    /// The parameters captured from the target if it matches
+    fn captures(&self, target: &str) -> Option<Vec<(String, String)>> {
+        let parts: Vec<&str> = target.split('/').collect();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => {
+                    params.push((name.clone(), part.to_string()));
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Threads that handlers with a timeout run on, so handlers that never return can't pile up
+/// threads without limit. Once every one is stuck, requests wait for a thread until they time out
+const HANDLER_THREADS: usize = 64;
+
+/// Runs a sync handler on the handler pool so we can stop waiting on it after the timeout
+///
+/// There's no way to stop a handler, so one that never returns keeps its thread, but the
+/// connection and its worker are freed up
+fn apply_with_timeout<F: FnOnce(&Request) -> Result<RouteResponse> + Send + 'static>(
+    f: F,
+    request: &Request,
+    timeout: Duration,
+) -> Result<RouteResponse> {
+    let (sender, receiver) = mpsc::channel();
+    let owned = request.clone();
+    let deadline = Instant::now() + timeout;
+    handler_pool().execute(move || {
+        // Nobody is waiting on a handler that only got a thread after its deadline
+        if Instant::now() < deadline {
+            let _ = sender.send(f(&owned));
+        }
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(response) => response,
+        Err(RecvTimeoutError::Disconnected) if Instant::now() < deadline => {
+            Err(anyhow!("Handler panicked"))
+        }
+        Err(_) => {
+            if let Some(session) = request.session() {
+                session.abandon();
+            }
+            Ok(timed_out(request, timeout))
+        }
+    }
+}
+
+fn handler_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        threadpool::Builder::new()
+            .num_threads(HANDLER_THREADS)
+            .thread_name(String::from("handler"))
+            .build()
+    })
+}
+
+fn with_timeout(
+    future: RouteFuture,
+    request: &Request,
+    timeout: Option<Duration>,
+) -> impl Future<Output = Result<RouteResponse>> {
+    let response = timeout.map(|timeout| (timeout, timed_out(request, timeout)));
+    let session = request.session().cloned();
+    async move {
+        match response {
+            Some((timeout, response)) => tokio::time::timeout(timeout, future)
+                .await
+                .unwrap_or_else(|_| {
+                    if let Some(session) = session {
+                        session.abandon();
+                    }
+                    Ok(response)
+                }),
+            None => future.await,
+        }
+    }
+}
+
+/// The response for a handler that missed its deadline, logged along with the request
+fn timed_out(request: &Request, timeout: Duration) -> RouteResponse {
+    (
+        "Gateway Timeout",
+        ResponseCode::Gateway_Timeout,
+        format!(
+            "Handler for {} {} didn't respond within {timeout:?}",
+            request.method(),
+            request.target()
+        ),
+    )
+        .into()
+}
+
+/// The response for a proxy or gateway that failed, logged along with the error
+///
+/// Errors caused by the other side taking too long get a 504, anything else a 502
+pub fn gateway_failed(request: &Request, err: &anyhow::Error) -> RouteResponse {
+    let timed_out = err
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            )
+        });
+    let context = format!(
+        "{} {} failed with error: {err:#}",
+        request.method(),
+        request.target(),
+    );
+    if timed_out {
+        ("Gateway Timeout", ResponseCode::Gateway_Timeout, context).into()
+    } else {
+        ("Bad Gateway", ResponseCode::Bad_Gateway, context).into()
+    }
+}
+
+/// Trailing slashes are dropped so that `/api` and `/api/` mean the same thing
+pub fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        String::from("/")
+    } else {
+        prefix.to_string()
+    }
+}
+
+/// The part of the target below a normalized prefix, or `None` if the target isn't the prefix or
+/// below it
+pub fn strip_prefix<'a>(prefix: &str, target: &'a str) -> Option<&'a str> {
+    if prefix == "/" {
+        Some(target)
+    } else {
+        target
+            .strip_prefix(prefix)
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Runs async handlers, and HTTP/2 connections, when the server isn't using the async runtime
+pub fn blocking_runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("Failed to start the runtime for async handlers")
+    })
+}
@@ -0,0 +1,455 @@
+use std::{
+    fmt::Debug,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use ahash::HashMap;
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::{
+    codes::ResponseCode,
+    cookie::{Cookie, SameSite},
+    request::Request,
+    route::RouteResponse,
+};
+
+/// Random bytes in a session id, which is sent base64 encoded
+const ID_BYTES: usize = 32;
+/// Length of an id once it's encoded
+const ID_LENGTH: usize = 43;
+/// How often the store is swept for sessions that have expired
+const PURGE_INTERVAL: Duration = Duration::from_mins(1);
+
+/// What a store keeps for each session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub data: Map<String, Value>,
+    pub created: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+/// Where sessions are kept between requests
+///
+/// Ids are always base64url, so they're safe to use as keys or file names as they are
+pub trait SessionStore: Debug + Send + Sync {
+    /// The session with the id, or `None` if there isn't one or it has expired
+    fn load(&self, id: &str) -> Result<Option<Record>>;
+    /// Saves the session, which the store can drop once `expires` has passed
+    fn save(&self, id: &str, record: &Record, expires: SystemTime) -> Result<()>;
+    fn remove(&self, id: &str) -> Result<()>;
+    /// Drops every session that has expired
+    fn purge(&self) -> Result<()>;
+}
+
+/// Keeps sessions in memory, so they're lost when the server stops
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (Record, SystemTime)>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Result<Option<Record>> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(id)
+            .filter(|(_, expires)| *expires > SystemTime::now())
+            .map(|(record, _)| record.clone()))
+    }
+
+    fn save(&self, id: &str, record: &Record, expires: SystemTime) -> Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), (record.clone(), expires));
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn purge(&self) -> Result<()> {
+        let now = SystemTime::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, (_, expires)| *expires > now);
+        Ok(())
+    }
+}
+
+/// Keeps each session as a JSON file in a directory, so they survive restarts
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+/// A session as it's written to its file
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    expires: SystemTime,
+    record: Record,
+}
+
+impl FileStore {
+    /// Creates the directory if it doesn't exist yet
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create session dir: {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        if !is_valid_id(id) {
+            return Err(anyhow!("Invalid session id"));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    fn read(path: &PathBuf) -> Result<Option<StoredRecord>> {
+        match fs::read(path) {
+            Ok(content) => Ok(Some(
+                serde_json::from_slice(&content).context("Session file is corrupt")?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context("Failed to read session file"),
+        }
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> Result<Option<Record>> {
+        Ok(Self::read(&self.path(id)?)?
+            .filter(|stored| stored.expires > SystemTime::now())
+            .map(|stored| stored.record))
+    }
+
+    /// Written to a temp file first, so a session is never read half written
+    fn save(&self, id: &str, record: &Record, expires: SystemTime) -> Result<()> {
+        let path = self.path(id)?;
+        let temp = path.with_extension("json.tmp");
+        let content = serde_json::to_vec(&StoredRecord {
+            expires,
+            record: record.clone(),
+        })?;
+        fs::write(&temp, content).context("Failed to write session file")?;
+        fs::rename(&temp, &path).context("Failed to write session file")
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).context("Failed to remove session file")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Files that can't be read are left alone, they may not be sessions
+    fn purge(&self) -> Result<()> {
+        let now = SystemTime::now();
+        for entry in fs::read_dir(&self.dir).context("Failed to read session dir")? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            if let Ok(Some(stored)) = Self::read(&path) {
+                if stored.expires <= now {
+                    fs::remove_file(&path).context("Failed to remove session file")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The session for a request, which handlers reach through `Request::session`
+///
+/// It's shared by every clone of the request, so changes made by the handler are saved once it
+/// returns. Values are stored as JSON, so any type that serializes can be kept
+#[derive(Debug, Clone)]
+pub struct Session {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// `None` until the session is first saved
+    id: Option<String>,
+    data: Map<String, Value>,
+    created: SystemTime,
+    /// Whether the client sent a session cookie, even one that had expired
+    had_cookie: bool,
+    ending: Ending,
+}
+
+/// What happens to the session once the request is done, later variants win over earlier ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Ending {
+    Save,
+    Rotate,
+    Destroy,
+    /// The handler timed out, but may still be changing the session
+    Abandon,
+}
+
+#[allow(dead_code)]
+impl Session {
+    fn new(id: Option<String>, record: Option<Record>, had_cookie: bool) -> Self {
+        let (data, created) = record.map_or_else(
+            || (Map::new(), SystemTime::now()),
+            |record| (record.data, record.created),
+        );
+        Self {
+            state: Arc::new(Mutex::new(State {
+                id,
+                data,
+                created,
+                had_cookie,
+                ending: Ending::Save,
+            })),
+        }
+    }
+
+    /// The value stored under `key`, an error if it doesn't fit `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let state = self.state.lock().unwrap();
+        state
+            .data
+            .get(key)
+            .map(|value| T::deserialize(value))
+            .transpose()
+            .with_context(|| format!("Session value {key} has the wrong type"))
+    }
+
+    pub fn insert<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<()> {
+        let value = serde_json::to_value(value)
+            .with_context(|| format!("Failed to serialize session value {key}"))?;
+        self.state
+            .lock()
+            .unwrap()
+            .data
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.state.lock().unwrap().data.remove(key);
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.state.lock().unwrap().data.contains_key(key)
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().data.clear();
+    }
+
+    /// Moves the session to a new id once the request is done, keeping its data
+    ///
+    /// Call it whenever the user's privileges change, ie: on login, so an id that was planted on
+    /// the client beforehand is worthless
+    pub fn rotate(&self) {
+        self.end(Ending::Rotate);
+    }
+
+    /// Deletes the session and the client's cookie once the request is done, ie: on logout
+    pub fn destroy(&self) {
+        self.end(Ending::Destroy);
+    }
+
+    /// Leaves the stored session as it was, for a request whose handler timed out and may still
+    /// be running
+    pub fn abandon(&self) {
+        self.end(Ending::Abandon);
+    }
+
+    fn end(&self, ending: Ending) {
+        let mut state = self.state.lock().unwrap();
+        state.ending = state.ending.max(ending);
+    }
+
+    /// The session's id, `None` for a new session that hasn't been saved yet
+    pub fn id(&self) -> Option<String> {
+        self.state.lock().unwrap().id.clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    pub cookie_name: String,
+    /// How long a session lasts without being used
+    pub idle_timeout: Duration,
+    /// How long a session lasts from when it was created, however much it's used
+    pub absolute_timeout: Duration,
+    /// Only send the cookie over HTTPS
+    pub secure: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            cookie_name: String::from("session"),
+            idle_timeout: Duration::from_mins(30),
+            absolute_timeout: Duration::from_hours(24),
+            secure: false,
+        }
+    }
+}
+
+/// Loads the session for each request before it's routed, and saves it once the handler is done
+///
+/// Sessions are only saved, and given a cookie, once something has been stored in them
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    options: SessionOptions,
+    last_purge: Arc<Mutex<Instant>>,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>, options: SessionOptions) -> Self {
+        Self {
+            store,
+            options,
+            last_purge: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Attaches the request's session to it, an empty one if it has none or it has timed out
+    pub fn start(&self, request: &mut Request) -> Result<Session> {
+        let cookies = request.cookies();
+        let sent = cookies.get(&self.options.cookie_name);
+        let id = sent.filter(|id| is_valid_id(id));
+        let record = match id {
+            Some(id) => self.store.load(id)?,
+            None => None,
+        };
+        let session = match (id, record) {
+            (Some(id), Some(record)) if self.is_live(&record) => {
+                Session::new(Some(id.to_string()), Some(record), true)
+            }
+            // Stores only drop expired sessions when they're purged, so they're removed as soon
+            // as they're seen instead
+            (Some(id), _) => {
+                self.store.remove(id)?;
+                Session::new(None, None, true)
+            }
+            (None, _) => Session::new(None, None, sent.is_some()),
+        };
+        request.set_session(session.clone());
+        Ok(session)
+    }
+
+    /// Saves the session after the handler is done with it, setting or removing its cookie
+    pub fn finish(&self, session: &Session, response: RouteResponse) -> Result<RouteResponse> {
+        let mut state = session.state.lock().unwrap();
+        if state.ending == Ending::Abandon {
+            return Ok(response);
+        }
+        if state.ending == Ending::Destroy || (state.id.is_none() && state.data.is_empty()) {
+            if let Some(id) = state.id.take() {
+                self.store.remove(&id)?;
+            }
+            return Ok(if state.had_cookie {
+                response.with_cookie(&self.cookie(Cookie::removal(&self.options.cookie_name)))?
+            } else {
+                response
+            });
+        }
+
+        let mut response = response;
+        if state.ending == Ending::Rotate || state.id.is_none() {
+            if let Some(old) = state.id.replace(generate_id()?) {
+                self.store.remove(&old)?;
+            }
+            let id = state.id.clone().unwrap_or_default();
+            response =
+                response.with_cookie(&self.cookie(Cookie::new(&self.options.cookie_name, id)))?;
+        }
+        let now = SystemTime::now();
+        let record = Record {
+            data: state.data.clone(),
+            created: state.created,
+            last_seen: now,
+        };
+        let expires =
+            (now + self.options.idle_timeout).min(state.created + self.options.absolute_timeout);
+        self.store
+            .save(state.id.as_deref().unwrap_or_default(), &record, expires)?;
+        drop(state);
+        self.purge();
+        Ok(response)
+    }
+
+    fn is_live(&self, record: &Record) -> bool {
+        let now = SystemTime::now();
+        now < record.last_seen + self.options.idle_timeout
+            && now < record.created + self.options.absolute_timeout
+    }
+
+    /// The server enforces the timeouts, so the cookie itself lasts until the browser closes
+    fn cookie(&self, cookie: Cookie) -> Cookie {
+        let cookie = cookie
+            .with_path("/")
+            .with_http_only()
+            .with_same_site(SameSite::Lax);
+        if self.options.secure {
+            cookie.with_secure()
+        } else {
+            cookie
+        }
+    }
+
+    /// Sweeps the store if it hasn't been for a while, failures are only logged as the request
+    /// itself went fine
+    fn purge(&self) {
+        {
+            let mut last_purge = self.last_purge.lock().unwrap();
+            if last_purge.elapsed() < PURGE_INTERVAL {
+                return;
+            }
+            *last_purge = Instant::now();
+        }
+        if let Err(err) = self.store.purge() {
+            warn!("Failed to purge expired sessions: {err:#}");
+        }
+    }
+}
+
+/// The response for a request whose session couldn't be loaded or saved, logged along with the
+/// error
+pub fn session_failed(err: &anyhow::Error) -> RouteResponse {
+    (
+        "Internal Server Error",
+        ResponseCode::Internal_Server_Error,
+        format!("Session store failed: {err:#}"),
+    )
+        .into()
+}
+
+fn generate_id() -> Result<String> {
+    let mut id = [0; ID_BYTES];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| anyhow!("Failed to generate session id"))?;
+    Ok(URL_SAFE_NO_PAD.encode(id))
+}
+
+/// Ids from the client are checked before they reach the store
+fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_LENGTH
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
@@ -0,0 +1,163 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tracing::{info, warn};
+
+use crate::listener::{self, Address};
+
+/// How often blocked listeners are woken up again until they've all stopped
+const WAKE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Coordinates a graceful shutdown between the listeners and the connection handlers
+///
+/// Once triggered the listeners stop accepting, and connections finish the request they're
+/// currently handling before closing instead of waiting for another one.
+#[derive(Debug)]
+pub struct Shutdown {
+    triggered: AtomicBool,
+    handed_off: AtomicBool,
+    started: Instant,
+    listeners: Mutex<Vec<Address>>,
+    accepting: AtomicUsize,
+    connections: AtomicUsize,
+    active: AtomicUsize,
+    requests: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            triggered: AtomicBool::new(false),
+            handed_off: AtomicBool::new(false),
+            started: Instant::now(),
+            listeners: Mutex::default(),
+            accepting: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::Acquire)
+    }
+
+    /// Registers a listener so it can be woken up from a blocking accept when shutting down
+    pub fn add_listener(&self, address: Address) {
+        self.listeners.lock().unwrap().push(address);
+    }
+
+    /// Tracks an accept loop as running until the returned guard is dropped
+    pub fn track_listener(&self) -> ListenerGuard<'_> {
+        self.accepting.fetch_add(1, Ordering::AcqRel);
+        ListenerGuard(self)
+    }
+
+    pub fn trigger(self: &Arc<Self>) {
+        if self.triggered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        info!("Shutting down, no longer accepting connections");
+        // A listening socket shared with another process, ie: after handing it off, may have the
+        // other process accept our wake up connection instead, so we keep trying until every
+        // accept loop has stopped
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            while shutdown.accepting.load(Ordering::Acquire) > 0 {
+                for address in shutdown.listeners.lock().unwrap().iter() {
+                    listener::wake(address);
+                }
+                thread::sleep(WAKE_INTERVAL);
+            }
+        });
+    }
+
+    /// Shuts down because another process has taken over the listening sockets
+    pub fn hand_off(self: &Arc<Self>) {
+        self.handed_off.store(true, Ordering::Release);
+        self.trigger();
+    }
+
+    pub fn is_handed_off(&self) -> bool {
+        self.handed_off.load(Ordering::Acquire)
+    }
+
+    /// Triggers the shutdown on SIGINT or SIGTERM, a second signal exits immediately
+    pub fn listen_for_signals(self: &Arc<Self>) -> Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_triggered() {
+                    warn!("Received signal {signal} again, exiting immediately");
+                    std::process::exit(1);
+                }
+                info!("Received signal {signal}");
+                shutdown.trigger();
+            }
+        });
+        Ok(())
+    }
+
+    /// Tracks a connection as active until the returned guard is dropped
+    pub fn track_connection(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::AcqRel);
+        ConnectionGuard(self)
+    }
+
+    pub fn request_served(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection turned away because the server was overloaded
+    pub fn connection_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub fn log_summary(&self) {
+        let active = self.active_connections();
+        info!(
+            "Shutdown complete after {:.1?}: served {} requests over {} connections, rejected {} connections while overloaded, dropped {active} in-flight connections",
+            self.started.elapsed(),
+            self.requests.load(Ordering::Relaxed),
+            self.connections.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+        );
+    }
+}
+
+pub struct ListenerGuard<'a>(&'a Shutdown);
+
+impl Drop for ListenerGuard<'_> {
+    fn drop(&mut self) {
+        self.0.accepting.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub struct ConnectionGuard<'a>(&'a Shutdown);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
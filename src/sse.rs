@@ -0,0 +1,157 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{body::BodyStream, codes::ResponseCode, request::Request, route::RouteResponse};
+
+/// How long a stream can go without an event before a comment is sent to keep it open
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A single Server-Sent Event
+///
+/// Data spanning several lines is sent as one `data` field per line, which the client joins back
+/// together
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    kind: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+#[allow(dead_code)]
+impl Event {
+    pub fn new<S: Into<String>>(data: S) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Sent back by the client in `Last-Event-ID` when it reconnects
+    #[must_use]
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The event type, clients listen for `message` when there isn't one
+    #[must_use]
+    pub fn with_event<S: Into<String>>(mut self, event: S) -> Self {
+        self.kind = Some(event.into());
+        self
+    }
+
+    /// How long the client should wait before reconnecting if the stream is lost
+    #[must_use]
+    pub const fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event in the `text/event-stream` format, line breaks in the id and event type would
+    /// start new fields so they're replaced with spaces
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        if let Some(id) = &self.id {
+            let _ = writeln!(out, "id: {}", single_line(id));
+        }
+        if let Some(kind) = &self.kind {
+            let _ = writeln!(out, "event: {}", single_line(kind));
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(out, "retry: {}", retry.as_millis());
+        }
+        for line in self.data.split('\n') {
+            let _ = writeln!(out, "data: {}", line.strip_suffix('\r').unwrap_or(line));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Pushes events to a client from any thread, the stream ends once every sender is dropped
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    sender: Sender<Event>,
+    last_event_id: Option<String>,
+}
+
+#[allow(dead_code)]
+impl EventSender {
+    /// Fails once the client has gone away, which is noticed on the next event or heartbeat
+    pub fn send(&self, event: Event) -> Result<()> {
+        self.sender
+            .send(event)
+            .map_err(|_| anyhow!("Event stream client disconnected"))
+    }
+
+    /// The id of the last event the client saw before reconnecting, so it can pick up from there
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+}
+
+/// Starts an event stream for the request, returning the sender for its events along with the
+/// response that streams them
+///
+/// The response is sent with chunked transfer coding and never cached, a comment is sent after
+/// each `HEARTBEAT_INTERVAL` without an event so intermediaries don't close the connection and a
+/// client that's gone away is noticed
+pub fn event_stream(request: &Request) -> (EventSender, RouteResponse) {
+    let (sender, receiver) = mpsc::channel();
+    let last_event_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .filter(|id| !id.is_empty())
+        .map(String::from);
+    let events = EventReader {
+        receiver,
+        pending: Vec::new(),
+        position: 0,
+    };
+    let response = RouteResponse::from(("", ResponseCode::Ok))
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+        .with_stream(BodyStream::new(events, None));
+    (
+        EventSender {
+            sender,
+            last_event_id,
+        },
+        response,
+    )
+}
+
+/// The body of an event stream, each read blocks until there's an event or a heartbeat is due
+struct EventReader {
+    receiver: Receiver<Event>,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl Read for EventReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            let encoded = match self.receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(event) => event.encode(),
+                Err(RecvTimeoutError::Timeout) => String::from(":\n\n"),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.pending = encoded.into_bytes();
+            self.position = 0;
+        }
+        let read = (&self.pending[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use rustls::{ServerConnection, StreamOwned};
+use tokio::{
+    io::{
+        AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader as AsyncBufReader, ReadBuf,
+    },
+    runtime::Handle,
+};
+
+/// A client connection from any of the listeners, with or without TLS
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<StreamOwned<ServerConnection, Self>>),
+}
+
+impl Stream {
+    /// The address of the client, clients connected through a Unix socket don't have one
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+            Self::Tls(stream) => stream.get_ref().peer_addr(),
+        }
+    }
+
+    pub const fn is_secure(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+            Self::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_write_timeout(timeout),
+            Self::Tls(stream) => stream.get_ref().set_write_timeout(timeout),
+        }
+    }
+
+    /// Flushes anything still buffered and, for TLS, tells the client we're done writing
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Self::Tls(stream) = self {
+            stream.conn.send_close_notify();
+        }
+        self.flush()
+    }
+
+    /// Moves the connection onto the current async runtime, TLS connections carry on with the
+    /// session that's already been started
+    pub fn into_async(self) -> io::Result<AsyncStream> {
+        let peer_addr = self.peer_addr();
+        match self {
+            Self::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                Ok(AsyncStream::new(
+                    tokio::net::TcpStream::from_std(stream)?,
+                    peer_addr,
+                ))
+            }
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                stream.set_nonblocking(true)?;
+                Ok(AsyncStream::new(
+                    tokio::net::UnixStream::from_std(stream)?,
+                    peer_addr,
+                ))
+            }
+            Self::Tls(stream) => {
+                let StreamOwned { conn, sock } = *stream;
+                Ok(AsyncStream::new_secure(
+                    AsyncTls {
+                        conn,
+                        io: sock.into_async()?,
+                        closing: false,
+                    },
+                    peer_addr,
+                ))
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Anything an async connection can be served over
+pub trait AsyncIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncIo for T {}
+
+/// A client connection accepted by the async runtime, with or without TLS
+pub struct AsyncStream {
+    io: Box<dyn AsyncIo>,
+    peer_addr: Option<SocketAddr>,
+    secure: bool,
+}
+
+impl AsyncStream {
+    pub fn new<T: AsyncIo + 'static>(io: T, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            io: Box::new(io),
+            peer_addr,
+            secure: false,
+        }
+    }
+
+    /// Wraps a stream that's been through a TLS handshake
+    pub fn new_secure<T: AsyncIo + 'static>(io: T, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            io: Box::new(io),
+            peer_addr,
+            secure: true,
+        }
+    }
+
+    pub const fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// The address of the client, clients connected through a Unix socket don't have one
+    pub const fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    /// For TLS this also tells the client we're done writing
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// A TLS session that was started with blocking I/O, continued over an async transport
+///
+/// tokio-rustls can only run sessions it started itself
+struct AsyncTls<I> {
+    conn: ServerConnection,
+    io: I,
+    closing: bool,
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncTls<I> {
+    /// Sends as much of the pending TLS data as the transport will take
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut PollIo {
+                io: &mut self.io,
+                cx,
+            }) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncRead for AsyncTls<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(read) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            // Reading can leave something to answer, like a key update
+            if let Poll::Ready(Err(err)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(err));
+            }
+            match this.conn.read_tls(&mut PollIo {
+                io: &mut this.io,
+                cx,
+            }) {
+                // The client went away without a close_notify, which is treated as the end
+                Ok(0) => return Poll::Ready(Ok(())),
+                Ok(_) => {
+                    if let Err(err) = this.conn.process_new_packets() {
+                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)));
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncWrite for AsyncTls<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            // rustls only buffers so much, anything past that has to wait until the transport
+            // takes some of it
+            let written = match this.conn.writer().write(buf) {
+                Ok(written) => written,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            let sent = this.poll_write_tls(cx);
+            if let Poll::Ready(Err(err)) = sent {
+                return Poll::Ready(Err(err));
+            }
+            if written > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(written));
+            }
+            if sent.is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_tls(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.conn.send_close_notify();
+            this.closing = true;
+        }
+        match this.poll_write_tls(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// Lets rustls read and write an async transport, with `WouldBlock` standing in for pending
+struct PollIo<'a, 'b, I> {
+    io: &'a mut I,
+    cx: &'a mut Context<'b>,
+}
+
+impl<I: AsyncRead + Unpin> Read for PollIo<'_, '_, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(err)) => Err(err),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<I: AsyncWrite + Unpin> Write for PollIo<'_, '_, I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// An async connection that first gives back bytes that were already read from it
+pub struct Rewind<I> {
+    buffered: Vec<u8>,
+    position: usize,
+    io: I,
+}
+
+impl<I> Rewind<I> {
+    pub const fn new(buffered: Vec<u8>, io: I) -> Self {
+        Self {
+            buffered,
+            position: 0,
+            io,
+        }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Rewind<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position < this.buffered.len() {
+            let remaining = &this.buffered[this.position..];
+            let read = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..read]);
+            this.position += read;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Rewind<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// A connection handed over to another protocol once its HTTP exchange is done, ie: after an
+/// `Upgrade`
+pub trait Socket: Read + Write + Send {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// A sync connection along with anything the client sent that's already been buffered
+pub struct BufferedStream(pub BufReader<Stream>);
+
+impl Read for BufferedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for BufferedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+impl Socket for BufferedStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.get_ref().set_read_timeout(timeout)
+    }
+}
+
+/// Blocking access to an async connection, for handlers that run on a blocking thread
+///
+/// Every call blocks on the runtime, so this must never be used from one of its workers
+pub struct SyncBridge {
+    io: AsyncBufReader<AsyncStream>,
+    handle: Handle,
+    read_timeout: Option<Duration>,
+}
+
+impl SyncBridge {
+    pub const fn new(io: AsyncBufReader<AsyncStream>, handle: Handle) -> Self {
+        Self {
+            io,
+            handle,
+            read_timeout: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn into_inner(self) -> AsyncBufReader<AsyncStream> {
+        self.io
+    }
+}
+
+impl Read for SyncBridge {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.io.read(buf);
+        match self.read_timeout {
+            // The timer has to be created on the runtime, which threads outside it only enter here
+            Some(timeout) => self
+                .handle
+                .block_on(async { tokio::time::timeout(timeout, read).await })
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => self.handle.block_on(read),
+        }
+    }
+}
+
+impl Write for SyncBridge {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle.block_on(self.io.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.block_on(self.io.flush())
+    }
+}
+
+impl Socket for SyncBridge {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+}
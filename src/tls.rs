@@ -0,0 +1,248 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use ahash::{HashMap, HashMapExt};
+use anyhow::{anyhow, Context, Result};
+use rustls::{
+    crypto::{ring::default_provider, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig, ServerConnection, StreamOwned,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+use crate::{
+    codes::ResponseCode,
+    hosts::strip_port,
+    request::{encode_path, Request},
+    route::RouteResponse,
+    stream::{AsyncStream, Stream},
+};
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct CertSource {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl CertSource {
+    fn load(&self, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .with_context(|| format!("Failed to open certificate: {}", self.cert.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to parse certificate: {}", self.cert.display()))?;
+        if certs.is_empty() {
+            return Err(anyhow!("No certificates found in: {}", self.cert.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("Failed to parse private key: {}", self.key.display()))?;
+        Ok(Arc::new(CertifiedKey::from_der(certs, key, provider)?))
+    }
+
+    /// The most recent modification time of either file
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        modified(&self.cert).max(modified(&self.key))
+    }
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    source: CertSource,
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+impl LoadedCert {
+    fn load(source: CertSource, provider: &CryptoProvider) -> Result<Self> {
+        let modified = source.modified();
+        let key = source.load(provider)?;
+        Ok(Self {
+            source,
+            key,
+            modified,
+        })
+    }
+
+    /// Reloads the certificate if the files changed, keeping the current one if that fails
+    fn reload(&mut self, provider: &CryptoProvider) {
+        let modified = self.source.modified();
+        if modified == self.modified {
+            return;
+        }
+        match self.source.load(provider) {
+            Ok(key) => {
+                info!("Reloaded certificate: {}", self.source.cert.display());
+                self.key = key;
+                self.modified = modified;
+            }
+            Err(err) => error!(
+                "Failed to reload certificate: {}, keeping the previous one. Error: {err:#}",
+                self.source.cert.display()
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Certs {
+    default: LoadedCert,
+    sni: HashMap<String, LoadedCert>,
+}
+
+/// Picks a certificate based on the SNI name the client asked for
+#[derive(Debug)]
+struct CertResolver {
+    provider: Arc<CryptoProvider>,
+    certs: RwLock<Certs>,
+}
+
+impl CertResolver {
+    fn reload(&self) {
+        let mut certs = self.certs.write().unwrap();
+        certs.default.reload(&self.provider);
+        for cert in certs.sni.values_mut() {
+            cert.reload(&self.provider);
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        let sni = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            certs.sni.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                certs.sni.get(&format!("*.{parent}"))
+            })
+        });
+        Some(sni.unwrap_or(&certs.default).key.clone())
+    }
+}
+
+/// Server side TLS configuration shared by every connection
+pub struct Tls {
+    config: Arc<ServerConfig>,
+    resolver: Arc<CertResolver>,
+}
+
+impl Tls {
+    /// Loads the default certificate, and any additional certificates for SNI
+    ///
+    /// Each SNI entry is in the form `HOST=CERT,KEY`, where host may be a wildcard such as
+    /// `*.example.com`
+    pub fn new<P: Into<PathBuf>>(cert: P, key: P, sni: &[String]) -> Result<Self> {
+        let provider = Arc::new(default_provider());
+        let default = LoadedCert::load(
+            CertSource {
+                cert: cert.into(),
+                key: key.into(),
+            },
+            &provider,
+        )?;
+
+        let mut certs = HashMap::new();
+        for entry in sni {
+            let (host, files) = entry
+                .split_once('=')
+                .context("SNI certificates must be in the form HOST=CERT,KEY")?;
+            let (cert, key) = files
+                .split_once(',')
+                .context("SNI certificates must be in the form HOST=CERT,KEY")?;
+            let source = CertSource {
+                cert: cert.into(),
+                key: key.into(),
+            };
+            if certs
+                .insert(
+                    host.trim_end_matches('.').to_ascii_lowercase(),
+                    LoadedCert::load(source, &provider)?,
+                )
+                .is_some()
+            {
+                return Err(anyhow!("Duplicate SNI certificate for host: {host}"));
+            }
+        }
+
+        let resolver = Arc::new(CertResolver {
+            provider: provider.clone(),
+            certs: RwLock::new(Certs {
+                default,
+                sni: certs,
+            }),
+        });
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            config: Arc::new(config),
+            resolver,
+        })
+    }
+
+    /// Wraps the connection in TLS, the handshake itself happens on the first read or write
+    pub fn accept(&self, stream: Stream) -> Result<Stream> {
+        let connection = ServerConnection::new(self.config.clone())?;
+        Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    /// Unlike `accept` the handshake is completed before this returns
+    pub async fn accept_async(&self, stream: AsyncStream) -> io::Result<AsyncStream> {
+        let peer_addr = stream.peer_addr();
+        let stream = TlsAcceptor::from(self.config.clone())
+            .accept(stream)
+            .await?;
+        Ok(AsyncStream::new_secure(stream, peer_addr))
+    }
+
+    /// Periodically checks the certificate files, and swaps in any that have changed
+    ///
+    /// New connections use the new certificates, existing ones are unaffected
+    pub fn spawn_reloader(&self) {
+        let resolver = self.resolver.clone();
+        thread::spawn(move || loop {
+            thread::sleep(RELOAD_INTERVAL);
+            resolver.reload();
+        });
+    }
+}
+
+/// Builds a redirect to the same target on the HTTPS port
+pub fn redirect_to_https(request: &Request, port: u16) -> RouteResponse {
+    let Some(host) = request.host() else {
+        return ("Missing Host header", ResponseCode::Bad_Request).into();
+    };
+    let mut location = format!("https://{}", strip_port(host));
+    if port != 443 {
+        location.push(':');
+        location.push_str(&port.to_string());
+    }
+    location.push_str(&encode_path(request.target()));
+    if let Some(query) = request.query() {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    // 301 allows clients to change the method to GET, which we only want for requests that were
+    // already safe to repeat that way
+    let code = if request.method().is_get() || request.method().is_head() {
+        ResponseCode::Moved_Permanently
+    } else {
+        ResponseCode::Permanent_Redirect
+    };
+    RouteResponse::from(("", code)).with_header("Location", location)
+}
@@ -0,0 +1,394 @@
+use std::{io, time::Duration};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use thiserror::Error;
+use tracing::error;
+
+use crate::{
+    codes::ResponseCode,
+    request::{Method, Request},
+    route::RouteResponse,
+    stream::Socket,
+};
+
+/// Appended to the client's key before hashing it, as defined by RFC 6455
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The largest message we'll accept, fragments included
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// How long we'll wait for the client to answer our close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_DATA: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+/// Handlers own the connection until they return, at which point it's closed if they haven't
+/// already done so
+#[allow(clippy::module_name_repetitions)]
+pub type WebSocketFn = fn(&Request, &mut WebSocket) -> Result<()>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// The client closed the connection, our reply has already been sent
+    Close {
+        code: Option<u16>,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Error)]
+enum FrameError {
+    #[error("{reason}")]
+    Protocol { code: u16, reason: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl FrameError {
+    fn protocol<S: Into<String>>(code: u16, reason: S) -> Self {
+        Self::Protocol {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Checks the opening handshake, returning a 101 that accepts it or the response turning it down
+///
+/// Requests that aren't trying to upgrade at all get a 426 telling them how to
+pub fn handshake(request: &Request) -> RouteResponse {
+    let headers = request.headers();
+    if !headers.contains_token("Upgrade", "websocket") {
+        return RouteResponse::from(("WebSocket upgrade required", ResponseCode::Upgrade_Required))
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        return RouteResponse::from((
+            "Unsupported WebSocket version",
+            ResponseCode::Upgrade_Required,
+        ))
+        .with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = headers.get("Sec-WebSocket-Key").filter(|key| {
+        STANDARD
+            .decode(key)
+            .is_ok_and(|decoded| decoded.len() == 16)
+    });
+    let Some(key) = key.filter(|_| {
+        request.method() == Method::GET && headers.contains_token("Connection", "upgrade")
+    }) else {
+        return ("Invalid WebSocket handshake", ResponseCode::Bad_Request).into();
+    };
+    RouteResponse::from(("", ResponseCode::Switching_Protocols))
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+}
+
+fn accept_key(key: &str) -> String {
+    STANDARD.encode(digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{GUID}").as_bytes(),
+    ))
+}
+
+/// A WebSocket connection, pings are answered and fragmented messages put back together while
+/// receiving
+///
+/// Anything the client does wrong closes the connection with the matching close code, after which
+/// every call returns an error
+pub struct WebSocket {
+    socket: Box<dyn Socket>,
+    close_sent: bool,
+    closed: bool,
+}
+
+#[allow(dead_code)]
+impl WebSocket {
+    pub fn new(socket: Box<dyn Socket>) -> Self {
+        Self {
+            socket,
+            close_sent: false,
+            closed: false,
+        }
+    }
+
+    /// Runs the handler, closing the connection once it's done
+    pub fn serve(socket: Box<dyn Socket>, request: &Request, handler: WebSocketFn) {
+        let mut websocket = Self::new(socket);
+        let (code, reason) = match handler(request, &mut websocket) {
+            Ok(()) => (NORMAL_CLOSURE, ""),
+            Err(err) => {
+                error!(
+                    "WebSocket handler for {} failed with error: {err:#}",
+                    request.target()
+                );
+                (INTERNAL_ERROR, "Internal error")
+            }
+        };
+        if let Err(err) = websocket.close(code, reason) {
+            error!("Failed to close WebSocket with error: {err}");
+        }
+    }
+
+    /// Waits for the next message, or the client closing the connection
+    pub fn recv(&mut self) -> Result<Message> {
+        if self.closed {
+            return Err(anyhow!("WebSocket is closed"));
+        }
+        match self.read_message() {
+            Ok(message) => Ok(message),
+            Err(FrameError::Protocol { code, reason }) => {
+                // The connection is unusable either way, so failing to say why doesn't matter
+                let _ = self.send_close(code, &reason);
+                self.closed = true;
+                Err(anyhow!("WebSocket protocol error: {reason}"))
+            }
+            Err(FrameError::Io(err)) => {
+                self.closed = true;
+                Err(err.into())
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<()> {
+        self.send(TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<()> {
+        self.send(BINARY, data)
+    }
+
+    /// The client's pong is consumed by `recv`
+    pub fn ping(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > 125 {
+            return Err(anyhow!("Ping payloads are limited to 125 bytes"));
+        }
+        self.send(PING, payload)
+    }
+
+    /// How long `recv` waits for the client before failing, `None` waits forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+
+    /// Sends a close frame and waits for the client's, discarding anything sent before it
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.send_close(code, reason)?;
+        self.socket.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        while !self.closed {
+            // The client doesn't get a say in how this ends any more
+            if self.read_message().is_err() {
+                self.closed = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        if self.close_sent {
+            return Err(anyhow!("WebSocket is closed"));
+        }
+        Ok(self.write_frame(opcode, payload)?)
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        // Control frames are limited to 125 bytes, the code takes 2 of them
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.write_frame(CLOSE, &payload)
+    }
+
+    fn read_message(&mut self) -> Result<Message, FrameError> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let received = message.as_ref().map_or(0, |(_, data)| data.len());
+            let frame = self.read_frame(MAX_MESSAGE_SIZE - received)?;
+            match frame.opcode {
+                PING => {
+                    if !self.close_sent {
+                        self.write_frame(PONG, &frame.payload)?;
+                    }
+                }
+                PONG => {}
+                CLOSE => return self.closed_by_client(&frame.payload),
+                CONTINUATION => {
+                    let Some((_, data)) = message.as_mut() else {
+                        return Err(FrameError::protocol(
+                            PROTOCOL_ERROR,
+                            "Continuation frame without a message to continue",
+                        ));
+                    };
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, data) = message.take().unwrap_or_default();
+                        return to_message(opcode, data);
+                    }
+                }
+                TEXT | BINARY => {
+                    if message.is_some() {
+                        return Err(FrameError::protocol(
+                            PROTOCOL_ERROR,
+                            "New message before the last one was finished",
+                        ));
+                    }
+                    if frame.fin {
+                        return to_message(frame.opcode, frame.payload);
+                    }
+                    message = Some((frame.opcode, frame.payload));
+                }
+                opcode => {
+                    return Err(FrameError::protocol(
+                        PROTOCOL_ERROR,
+                        format!("Unknown opcode: {opcode:#x}"),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Answers the client's close frame with our own, unless we started the close
+    fn closed_by_client(&mut self, payload: &[u8]) -> Result<Message, FrameError> {
+        let (code, reason) = match payload {
+            [] => (None, String::new()),
+            [_] => {
+                return Err(FrameError::protocol(
+                    PROTOCOL_ERROR,
+                    "Close frame with a truncated code",
+                ))
+            }
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                // 1005, 1006 and 1015 are only for reporting, they must never be sent
+                if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                    return Err(FrameError::protocol(
+                        PROTOCOL_ERROR,
+                        format!("Invalid close code: {code}"),
+                    ));
+                }
+                let reason = String::from_utf8(reason.to_vec()).map_err(|_| {
+                    FrameError::protocol(INVALID_DATA, "Close reason is not valid UTF-8")
+                })?;
+                (Some(code), reason)
+            }
+        };
+        self.send_close(code.unwrap_or(NORMAL_CLOSURE), "")?;
+        self.closed = true;
+        Ok(Message::Close { code, reason })
+    }
+
+    fn read_frame(&mut self, limit: usize) -> Result<Frame, FrameError> {
+        let mut head = [0; 2];
+        self.socket.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(FrameError::protocol(
+                PROTOCOL_ERROR,
+                "Reserved bits set without an extension",
+            ));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(FrameError::protocol(
+                PROTOCOL_ERROR,
+                "Client frames must be masked",
+            ));
+        }
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.socket.read_exact(&mut length)?;
+                u64::from(u16::from_be_bytes(length))
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.socket.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => u64::from(length),
+        };
+        if opcode & 0x8 != 0 && (!fin || length > 125) {
+            return Err(FrameError::protocol(
+                PROTOCOL_ERROR,
+                "Control frames must be unfragmented and at most 125 bytes",
+            ));
+        }
+        let length = usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= limit)
+            .ok_or_else(|| FrameError::protocol(MESSAGE_TOO_BIG, "Message is too big"))?;
+
+        let mut mask = [0; 4];
+        self.socket.read_exact(&mut mask)?;
+        let mut payload = vec![0; length];
+        self.socket.read_exact(&mut payload)?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Server frames are never masked or fragmented
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        let length = payload.len();
+        if length < 126 {
+            frame.push(u8::try_from(length).unwrap_or_default());
+        } else if let Ok(length) = u16::try_from(length) {
+            frame.push(126);
+            frame.extend_from_slice(&length.to_be_bytes());
+        } else {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        self.socket.write_all(&frame)?;
+        self.socket.flush()
+    }
+}
+
+fn to_message(opcode: u8, data: Vec<u8>) -> Result<Message, FrameError> {
+    if opcode == TEXT {
+        String::from_utf8(data)
+            .map(Message::Text)
+            .map_err(|_| FrameError::protocol(INVALID_DATA, "Text message is not valid UTF-8"))
+    } else {
+        Ok(Message::Binary(data))
+    }
+}